@@ -1,15 +1,19 @@
 use std::env;
+use std::time::Duration;
 use actix_web::{web, get, App, Error, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web_actors::ws;
 mod mq;
 use mq::websocks;
 use mq::partition::PartitionDispacher;
+use mq::config::MqConfig;
+use mq::meta::{DataMeta, TopicHash};
 
 struct AppState {
     dispacher: PartitionDispacher
 }
 
 const MAX_FRAME_SIZE: usize = 128_384; // 16KiB
+const RETENTION_INTERVAL_SECS: u64 = 3600;
 
 #[get("/ws/{cid}")]
 async fn websocket_service(req: HttpRequest, stream: web::Payload, data: web::Data<AppState>) -> Result<HttpResponse, Error> {
@@ -17,7 +21,9 @@ async fn websocket_service(req: HttpRequest, stream: web::Payload, data: web::Da
     let client_id: &str = req.match_info().get("cid").unwrap();
     let actor = websocks::WsSession {
         client_id: client_id.to_string(),
-        dispacher: data.dispacher.clone()
+        dispacher: data.dispacher.clone(),
+        binary: false,
+        ack: false
     };
     ws::WsResponseBuilder::new(actor, &req, stream)
         .codec(actix_http::ws::Codec::new())
@@ -28,9 +34,15 @@ async fn websocket_service(req: HttpRequest, stream: web::Payload, data: web::Da
 
 async fn trim_handler(req: HttpRequest, data: web::Data<AppState>) -> impl Responder{
     let offset: &str = req.match_info().get("offset").unwrap();
-    let days: u16 = offset.parse::<u16>().unwrap();
-    data.dispacher.clone().trim_data(days);
-    let resp: websocks::ErrResp = websocks::ErrResp{rs:true, detail:"".to_string()};
+    let days: u16 = match offset.parse::<u16>() {
+        Ok(days) => days,
+        Err(err) => {
+            let resp = websocks::ErrResp{rs:false, detail:format!("Invalid days {offset}:{err}")};
+            return HttpResponse::BadRequest().body(serde_json::to_string(&resp).unwrap());
+        }
+    };
+    let removed = data.dispacher.clone().trim_data(days).await;
+    let resp = websocks::TrimResp{rs:true, removed};
     let json = serde_json::to_string(&resp).unwrap();
     HttpResponse::Ok().body(json)
 }
@@ -42,13 +54,68 @@ async fn status_handler(_req: HttpRequest, data: web::Data<AppState>) -> impl Re
 }
 
 async fn publish_handler(_req: HttpRequest, data: web::Data<AppState>, msg: web::Json<websocks::Message>) -> impl Responder {
-    let mut message = msg;
-    data.dispacher.clone().dispach_message(&mut message);
-    let resp: websocks::ErrResp = websocks::ErrResp{rs:true, detail:"".to_string()};
+    let mut message = msg.into_inner();
+    if message.topic.is_none() {
+        let resp = websocks::ErrResp{rs:false, detail:"Missing topic".to_string()};
+        return HttpResponse::BadRequest().body(serde_json::to_string(&resp).unwrap());
+    }
+    match data.dispacher.clone().publish(&mut message).await {
+        Ok(resp) => HttpResponse::Ok().body(serde_json::to_string(&resp).unwrap()),
+        Err(err) => {
+            let resp = websocks::ErrResp{rs:false, detail:err};
+            HttpResponse::InternalServerError().body(serde_json::to_string(&resp).unwrap())
+        }
+    }
+}
+
+async fn publish_batch_handler(_req: HttpRequest, data: web::Data<AppState>, msgs: web::Json<Vec<websocks::Message>>) -> impl Responder {
+    let messages = msgs.into_inner();
+    if let Some(pos) = messages.iter().position(|m| m.topic.is_none()) {
+        let resp = websocks::ErrResp{rs:false, detail:format!("Missing topic in message {pos}")};
+        return HttpResponse::BadRequest().body(serde_json::to_string(&resp).unwrap());
+    }
+    match data.dispacher.clone().publish_batch(messages).await {
+        Ok(nonces) => HttpResponse::Ok().body(serde_json::to_string(&websocks::BatchResp{rs:true, nonces}).unwrap()),
+        Err(err) => {
+            let resp = websocks::ErrResp{rs:false, detail:err};
+            HttpResponse::InternalServerError().body(serde_json::to_string(&resp).unwrap())
+        }
+    }
+}
+
+async fn redrive_handler(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let topic: &str = req.match_info().get("topic").unwrap();
+    let redriven = data.dispacher.clone().redrive(topic).await;
+    let resp = websocks::RedriveResp{rs:true, topic:topic.to_string(), redriven};
     let json = serde_json::to_string(&resp).unwrap();
     HttpResponse::Ok().body(json)
 }
 
+async fn partitions_handler(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let count_str: &str = req.match_info().get("count").unwrap();
+    let count: u16 = match count_str.parse::<u16>() {
+        Ok(count) => count,
+        Err(err) => {
+            let resp = websocks::ErrResp{rs:false, detail:format!("Invalid partition count {count_str}:{err}")};
+            return HttpResponse::BadRequest().body(serde_json::to_string(&resp).unwrap());
+        }
+    };
+    match data.dispacher.clone().add_partitions(count) {
+        Ok(()) => {
+            let resp = data.dispacher.partitions_status();
+            HttpResponse::Accepted().body(serde_json::to_string(&resp).unwrap())
+        },
+        Err(err) => {
+            let resp = websocks::ErrResp{rs:false, detail:err};
+            HttpResponse::BadRequest().body(serde_json::to_string(&resp).unwrap())
+        }
+    }
+}
+
+async fn partitions_status_handler(_req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let resp = data.dispacher.partitions_status();
+    HttpResponse::Ok().body(serde_json::to_string(&resp).unwrap())
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -73,15 +140,91 @@ async fn main() -> std::io::Result<()> {
         .help("Segment count for storage")
         .default_value("10")
         .takes_value(true))
+    .arg(clap::Arg::with_name("AckTimeout")
+        .long("ack-timeout")
+        .value_name("milliseconds")
+        .help("Redeliver a message when it is not acked within this time [default: 30000]")
+        .takes_value(true))
+    .arg(clap::Arg::with_name("RetentionDays")
+        .long("retention-days")
+        .value_name("days")
+        .help("Trim messages older than this many days every hour")
+        .takes_value(true))
+    .arg(clap::Arg::with_name("Rehash")
+        .long("rehash")
+        .help("Move the topics of a data directory on the legacy hash to FNV-1a"))
+    .arg(clap::Arg::with_name("Config")
+        .short('c')
+        .long("config")
+        .value_name("config file")
+        .help("Json file with ack timeout and per topic settings")
+        .takes_value(true))
     .get_matches();
     let port_str = matches.value_of("port").unwrap();
     let segment_str = matches.value_of("Segment").unwrap();
 
 
     let port:u16 = port_str.parse().unwrap();
-    let segment:u16 = segment_str.parse().unwrap();
+    let mut segment:u16 = segment_str.parse().unwrap();
+    let mut config = match matches.value_of("Config") {
+        Some(path) => MqConfig::from_file(path).unwrap(),
+        None => MqConfig::default()
+    };
+    if let Some(ack_timeout_str) = matches.value_of("AckTimeout") {
+        config.ack_timeout_ms = ack_timeout_str.parse().unwrap();
+    }
+    if let Some(retention_days_str) = matches.value_of("RetentionDays") {
+        config.retention_days = Some(retention_days_str.parse().unwrap());
+    }
+    let retention_days = config.retention_days;
+
+    let mut meta = DataMeta::load().unwrap();
+    println!("data directory uses {:?} topic hash", meta.hash);
+    match meta.partitions {
+        Some(partitions) => {
+            if matches.occurrences_of("Segment") > 0 && partitions != segment {
+                eprintln!("data directory has {partitions} partitions, segment {segment} ignored");
+            }
+            segment = partitions;
+        }
+        None => {
+            meta.partitions = Some(segment);
+            meta.save().unwrap();
+        }
+    }
+    // topics already stored are moved to the partition the new pins or hash route them to
+    let pins = config.pins();
+    if pins != meta.pins {
+        println!("topic pins changed from {:?} to {pins:?}", meta.pins);
+        meta.pins = pins;
+        meta.migrating = true;
+    }
+    if matches.is_present("Rehash") && meta.hash != TopicHash::Fnv1a {
+        println!("rehashing topics with {:?}", TopicHash::Fnv1a);
+        meta.hash = TopicHash::Fnv1a;
+        meta.migrating = true;
+    }
+    meta.save().unwrap();
+    let migrating = meta.migrating;
+    let dispatcher = PartitionDispacher::from_number(segment, config, meta);
+    if migrating {
+        match dispatcher.clone().resume_migration().await {
+            Ok(moved) => println!("finished moving topics to their partitions:{moved:?}"),
+            Err(err) => eprintln!("finish moving topics to their partitions with error:{err}")
+        }
+    }
 
-    let dispatcher = PartitionDispacher::from_number(segment);
+    if let Some(days) = retention_days {
+        let mut dispacher = dispatcher.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(RETENTION_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                let removed = dispacher.trim_data(days).await;
+                println!("retention of {days} days removed:{removed:?}");
+            }
+        });
+    }
 
     let app_state = web::Data::new(AppState {
         dispacher:dispatcher
@@ -89,8 +232,12 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || App::new()
                             .service(websocket_service)
                             .route("/api/publish", web::post().to(publish_handler))
+                            .route("/api/publish/batch", web::post().to(publish_batch_handler))
                             .route("/api/status", web::get().to(status_handler))
-                            .route("/api/trim/{offset}/days", web::get().to(trim_handler))
+                            .route("/api/trim/{offset}/days", web::post().to(trim_handler))
+                            .route("/api/dlq/{topic}/redrive", web::post().to(redrive_handler))
+                            .route("/api/partitions", web::get().to(partitions_status_handler))
+                            .route("/api/partitions/{count}", web::post().to(partitions_handler))
                             .app_data(app_state.clone()))
         .bind(("0.0.0.0", port))?
         .run()
@@ -0,0 +1,72 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use serde::Deserialize;
+
+/// Settings of a single topic
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TopicConfig {
+    /// deliveries before a message is moved to `<topic>.dlq`, unlimited when absent
+    pub max_deliveries: Option<u32>,
+    /// lifetime of messages published without their own `ttl_ms`
+    pub ttl_ms: Option<u64>,
+    /// oldest messages are removed once the topic holds more than this many
+    pub max_messages: Option<u64>,
+    /// oldest messages are removed once the stored messages of the topic take more bytes than this
+    pub max_bytes: Option<u64>,
+    /// keep only the latest message per `key`, a message without payload deletes the key
+    pub compacted: bool,
+    /// partition the topic is pinned to instead of the hashed one
+    pub partition: Option<u16>,
+}
+
+impl TopicConfig {
+    pub fn has_retention(&self) -> bool {
+        self.max_messages.is_some() || self.max_bytes.is_some()
+    }
+}
+
+/// Runtime settings shared by every partition
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MqConfig {
+    /// how long a delivered message may stay unacked before it is redelivered
+    pub ack_timeout_ms: u64,
+    /// messages older than this many days are trimmed automatically
+    pub retention_days: Option<u16>,
+    /// a publish repeating a uid of the topic within this window returns the first nonce, 0 disables it
+    pub dedup_window_ms: u64,
+    pub topics: HashMap<String, TopicConfig>,
+}
+
+impl Default for MqConfig {
+    fn default() -> Self {
+        MqConfig {
+            ack_timeout_ms: 30_000,
+            retention_days: None,
+            dedup_window_ms: 60_000,
+            topics: HashMap::new(),
+        }
+    }
+}
+
+impl MqConfig {
+    /// Load the settings from a json file
+    pub fn from_file(path: &str) -> Result<MqConfig, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| format!("read {path}:{err}"))?;
+        serde_json::from_str(text.as_str()).map_err(|err| format!("parse {path}:{err}"))
+    }
+
+    pub fn ack_timeout(&self) -> Duration {
+        Duration::from_millis(self.ack_timeout_ms)
+    }
+
+    pub fn topic(&self, topic: &str) -> TopicConfig {
+        self.topics.get(topic).cloned().unwrap_or_default()
+    }
+
+    /// topic -> partition of every pinned topic
+    pub fn pins(&self) -> BTreeMap<String, u16> {
+        self.topics.iter().filter_map(|(topic, tc)| Some((topic.clone(), tc.partition?))).collect()
+    }
+}
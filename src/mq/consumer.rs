@@ -1,20 +1,44 @@
 use actix::{Actor, Addr, Context, Handler};
-use std::collections::HashMap;
-use sled::IVec;
-use super::websocks::{WsSession, InnerMessage, make_key, vectu64};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use super::websocks::{WsSession, InnerMessage, Message, make_key, topic_range, make_offset_key, group_sub_id, sub_group, dlq_topic, key_nonce, vectu64, i64to_vec, is_pattern, topic_matches};
+use super::storage::stored_topics;
+use super::partition::PartitionDispacher;
+use super::config::MqConfig;
 use actix::prelude::*;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
 
-    
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct RegisterCmd {
+    /// literal topics or patterns, a pattern covers the matching topics stored now and later
     pub topics: Vec<String>,
     pub client_id: String,
-    pub offset: u64,
+    /// consumer group sharing one offset, `None` consumes alone
+    pub group: Option<String>,
+    /// explicit start nonce, `None` resumes from the committed offset
+    pub offset: Option<u64>,
+    /// start at the first message published at or after this epoch ms, used without `offset`
+    pub since: Option<i64>,
+    /// only messages carrying all of these headers are delivered
+    pub filter: Option<HashMap<String, String>>,
+    /// the client acks every message, otherwise the offset is committed once a message is sent
+    pub ack: bool,
     pub addr: Addr<WsSession>
 }
 
+/// How a subscription starts and what it receives
+#[derive(Debug, Clone, Default)]
+pub struct SubscribeOpts {
+    pub group: Option<String>,
+    pub offset: Option<u64>,
+    pub since: Option<i64>,
+    pub filter: Option<HashMap<String, String>>,
+    pub ack: bool,
+}
+
 
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -22,17 +46,177 @@ pub struct ClearConnCmd {
     pub client_id: String
 }
 
+/// a message of `topic` was written to the partition
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct NewMessageCmd {
+    pub topic: String,
+    pub nonce: u64
+}
+
+/// dispacher used to re-publish dead letters, sent once all partitions are up
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AttachDispacherCmd(pub PartitionDispacher);
+
+/// client processed the messages, they will not be delivered again
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AckCmd {
+    pub client_id: String,
+    pub nonces: Vec<u64>
+}
+
+/// client rejected the messages, they are redelivered right away
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct NackCmd {
+    pub client_id: String,
+    pub nonces: Vec<u64>
+}
+
+/// client stops receiving `topics`, literal ones or patterns, its other subscriptions stay
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UnsubscribeCmd {
+    pub client_id: String,
+    pub topics: Vec<String>
+}
+
+/// topics with a subscription on the partition
+#[derive(Message)]
+#[rtype(result = "Vec<String>")]
+pub struct SubscribedTopicsCmd;
+
+/// pattern subscriptions of the partition, to be repeated on a new one
+#[derive(Message)]
+#[rtype(result = "Vec<(HandoverMember, String)>")]
+pub struct PatternsCmd;
+
+/// give up `topic` because it moves to another partition, answered with its subscriptions
+#[derive(Message)]
+#[rtype(result = "Handover")]
+pub struct HandoverCmd {
+    pub topic: String
+}
+
+/// subscriptions and committed offsets of a topic leaving the partition
+#[derive(Default)]
+pub struct Handover {
+    pub members: Vec<HandoverMember>,
+    /// sub_id -> committed offset, connected or not
+    pub offsets: Vec<(String, u64)>
+}
+
+/// a connected client that consumed the topic
+pub struct HandoverMember {
+    pub client_id: String,
+    pub group: Option<String>,
+    pub addr: Addr<WsSession>,
+    pub filter: Option<HashMap<String, String>>,
+    pub credit: Option<u64>,
+    pub ack: bool
+}
+
+/// `topic` is being copied in from another partition, patterns leave it alone until its offsets are imported
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ArrivingTopicCmd {
+    pub topic: String
+}
+
+/// committed offsets of `topic` taken over from another partition
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ImportOffsetsCmd {
+    pub topic: String,
+    pub offsets: Vec<(String, u64)>
+}
+
+/// client allows at most `credit` unacked messages on each of `topics`, or on all its other
+/// topics together when empty; only subscriptions in ack mode have unacked messages
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CreditCmd {
+    pub client_id: String,
+    pub topics: Vec<String>,
+    pub credit: u64
+}
+
+/// prefetch window granted by a client with the `credit` command
+#[derive(Default)]
+pub struct CreditWindow {
+    /// window of the topics without their own one
+    pub default: Option<u64>,
+    pub topics: HashMap<String, u64>
+}
+
+impl CreditWindow {
+    fn of(&self, topic: &str) -> Option<u64> {
+        self.topics.get(topic).copied().or(self.default)
+    }
+}
+
+/// what one member of a subscription asked for
+#[derive(Default)]
+pub struct MemberTopics {
+    /// literal topics and the topics its patterns matched
+    pub topics: Vec<String>,
+    /// topics subscribed by name, they stay when a pattern matching them is unsubscribed
+    pub named: Vec<String>,
+    pub patterns: Vec<String>,
+    /// topics unsubscribed by name that its patterns must not match again
+    pub excluded: Vec<String>
+}
+
+/// a delivered message waiting for its ack
+pub struct InFlight {
+    pub topic: String,
+    /// member the message was sent to, empty when it has to be reassigned
+    pub client_id: String,
+    pub deadline: Instant,
+    pub attempts: u32
+}
 
+///
+///  A subscription is keyed by its `sub_id`: the client_id of a lone consumer,
+///  or `group_sub_id(group)` for a consumer group whose members share the offset.
+///
 pub struct ConsumerActor {
-    pub connection_offset: HashMap<String, u64>,
+    /// sub_id -> topic -> next nonce to deliver
+    pub connection_offset: HashMap<String, HashMap<String, u64>>,
+    /// client_id -> session
     pub connection_addr: HashMap<String, Addr<WsSession>>,
     pub connection_count: u16,
+    /// sub_id -> subscribed topics
     pub connection_topics: HashMap<String, Vec<String>>,
+    /// sub_id -> connected client_ids receiving its messages
+    pub members: HashMap<String, Vec<String>>,
+    /// sub_id -> round-robin position among its members
+    pub member_cursor: HashMap<String, usize>,
+    /// sub_id -> nonce -> delivered but unacked message
+    pub in_flight: HashMap<String, BTreeMap<u64, InFlight>>,
+    /// client_id -> unacked messages it accepts, unlimited without entry
+    pub credit_window: HashMap<String, CreditWindow>,
+    /// sub_id + topic whose delivery stopped on a full session mailbox
+    pub stalled: HashSet<(String, String)>,
+    /// sub_id -> topic -> headers a message needs to be delivered
+    pub filters: HashMap<String, HashMap<String, HashMap<String, String>>>,
+    /// sub_id -> client_id -> what the member subscribed to, a member only gets messages of its own topics;
+    /// connection_topics holds the union over the members
+    pub member_topics: HashMap<String, HashMap<String, MemberTopics>>,
+    /// sub_id whose members ack their messages, set by the latest subscribe
+    pub ack_subs: HashSet<String>,
+    /// topics copied in from another partition that still serves their subscribers
+    pub arriving: HashSet<String>,
+    pub config: MqConfig,
+    pub dispacher: Option<PartitionDispacher>,
     pub db: sled::Db,
-    pub range_idx: sled::Tree,
-    pub day_idx: sled::Tree,
     pub main_idx: sled::Tree,
-    pub nonce_idx: sled::Tree,
+    ///  time_idx - timestamp + nonce -> topic
+    pub time_idx: sled::Tree,
+    ///  offset_idx - sub_id + topic -> committed offset
+    pub offset_idx: sled::Tree,
 }
 
 impl Handler<RegisterCmd> for ConsumerActor {
@@ -40,49 +224,429 @@ impl Handler<RegisterCmd> for ConsumerActor {
 
     fn handle(&mut self, msg: RegisterCmd, _ctx: &mut Self::Context) -> Self::Result {
         let client_id = msg.client_id.as_str();
-        println!("consumer:{client_id} subscribe topics:{:?}", msg.topics);
-        
-        if self.connection_offset.contains_key(client_id){
-            *self.connection_offset.get_mut(client_id).unwrap() = msg.offset;
-        }else{
-            self.connection_offset.insert(client_id.to_string(), msg.offset);
-        }
-        if self.connection_addr.contains_key(client_id){
-            *self.connection_addr.get_mut(client_id).unwrap() = msg.addr;
-        }else{
-            self.connection_addr.insert(client_id.to_string(), msg.addr);
-        }
-        if self.connection_topics.contains_key(client_id){
-            let tps =  self.connection_topics.get_mut(client_id).unwrap();
-            for topic in msg.topics {
-               tps.insert(0, topic);
+        let sub_id = match &msg.group {
+            Some(group) => group_sub_id(group.as_str()),
+            None => client_id.to_string()
+        };
+        println!("consumer:{client_id} subscribe topics:{:?} as {sub_id}", msg.topics);
+        if msg.ack {
+            self.ack_subs.insert(sub_id.clone());
+        } else {
+            self.ack_subs.remove(&sub_id);
+        }
+
+        let (patterns, mut topics): (Vec<String>, Vec<String>) = msg.topics.into_iter().partition(|t| is_pattern(t));
+        let named = topics.clone();
+        if !patterns.is_empty() {
+            for pattern in patterns.iter() {
+                // kept for the topics the pattern matches later
+                if let Some(filter) = &msg.filter {
+                    self.filters.entry(sub_id.clone()).or_default().insert(pattern.clone(), filter.clone());
+                }
+            }
+            for topic in stored_topics(&self.main_idx) {
+                if self.arriving.contains(&topic) {
+                    continue;
+                }
+                if !topics.contains(&topic) && patterns.iter().any(|p| topic_matches(p, topic.as_str())) {
+                    topics.push(topic);
+                }
+            }
+        }
+
+        for topic in topics.iter() {
+            // a filter replaces the one of the subscription, no filter keeps it
+            if let Some(filter) = &msg.filter {
+                self.filters.entry(sub_id.clone()).or_default().insert(topic.clone(), filter.clone());
+            }
+            let known = self.connection_offset.get(&sub_id).and_then(|offsets| offsets.get(topic)).is_some();
+            // a member joining a running group keeps the group position
+            if known && msg.offset.is_none() && msg.since.is_none() {
+                continue;
+            }
+            let offset = match (msg.offset, msg.since) {
+                (Some(offset), _) => offset,
+                (None, Some(since)) => self.offset_since(topic.as_str(), since),
+                (None, None) => self.committed_offset(sub_id.as_str(), topic.as_str())
+            };
+            println!("consumer:{sub_id} start {topic} from {offset}");
+            self.connection_offset
+                .entry(sub_id.clone())
+                .or_default()
+                .insert(topic.clone(), offset);
+        }
+        self.connection_addr.insert(client_id.to_string(), msg.addr);
+        let members = self.members.entry(sub_id.clone()).or_default();
+        if !members.iter().any(|m| m == client_id) {
+            members.push(client_id.to_string());
+        }
+        let member = self.member_topics.entry(sub_id.clone()).or_default().entry(client_id.to_string()).or_default();
+        // subscribing again, by name or with the pattern, lifts an exclusion
+        member.excluded.retain(|t| !topics.contains(t));
+        for topic in named {
+            if !member.named.contains(&topic) {
+                member.named.push(topic);
+            }
+        }
+        for pattern in patterns {
+            if !member.patterns.contains(&pattern) {
+                member.patterns.push(pattern);
+            }
+        }
+        for topic in topics.iter() {
+            if !member.topics.contains(topic) {
+                member.topics.push(topic.clone());
+            }
+        }
+        let tps = self.connection_topics.entry(sub_id.clone()).or_default();
+        for topic in topics.iter() {
+            if !tps.contains(topic) {
+                tps.insert(0, topic.clone());
             }
-        }else{
-            self.connection_topics.insert(client_id.to_string(), msg.topics);
         }
         self.connection_count +=1;
+        // catch up with the backlog, later messages arrive through NewMessageCmd
+        let now = Instant::now();
+        for topic in topics {
+            self.deliver_topic(sub_id.as_str(), topic.as_str(), now);
+        }
+    }
+}
+
+impl Handler<NewMessageCmd> for ConsumerActor {
+    type Result = ();
+    fn handle(&mut self, msg: NewMessageCmd, _ctx: &mut Self::Context) {
+        let now = Instant::now();
+        self.match_patterns(msg.topic.as_str());
+        let subscribers: Vec<String> = self.connection_topics
+            .iter()
+            .filter(|(_, topics)| topics.contains(&msg.topic))
+            .filter(|(sub_id, _)| {
+                // a cursor already past the nonce has nothing new to fetch
+                self.connection_offset
+                    .get(*sub_id)
+                    .and_then(|offsets| offsets.get(&msg.topic))
+                    .is_some_and(|ofs| *ofs <= msg.nonce)
+            })
+            .map(|(sub_id, _)| sub_id.clone())
+            .collect();
+        for sub_id in subscribers {
+            self.deliver_topic(sub_id.as_str(), msg.topic.as_str(), now);
+        }
     }
 }
 
 impl Handler<ClearConnCmd> for ConsumerActor {
     type Result = ();
     fn handle(&mut self, msg: ClearConnCmd, _ctx: &mut Self::Context) {
-        let mut ct = 0;
-        if self.connection_addr.contains_key(msg.client_id.as_str()){
-            self.connection_addr.remove(msg.client_id.as_str());
-            ct+=1;
+        let client_id = msg.client_id.as_str();
+        if self.connection_addr.remove(client_id).is_none() {
+            return;
+        }
+        let now = Instant::now();
+        let mut left: Vec<String> = vec![];
+        let mut orphaned: Vec<(String, String)> = vec![];
+        for (sub_id, members) in self.members.iter_mut() {
+            if let Some(pos) = members.iter().position(|m| m == client_id) {
+                members.remove(pos);
+                let gone = self.member_topics.get_mut(sub_id).and_then(|m| m.remove(client_id)).unwrap_or_default();
+                if members.is_empty() {
+                    left.push(sub_id.clone());
+                    continue;
+                }
+                if let Some(flights) = self.in_flight.get_mut(sub_id) {
+                    // rebalance: hand the messages of the gone member to the others
+                    for flight in flights.values_mut().filter(|f| f.client_id == client_id) {
+                        flight.client_id = String::new();
+                        flight.deadline = now;
+                    }
+                }
+                // the group stops consuming the topics only the gone member subscribed to
+                let others = self.member_topics.get(sub_id);
+                for topic in gone.topics {
+                    if !others.is_some_and(|o| o.values().any(|m| m.topics.contains(&topic))) {
+                        orphaned.push((sub_id.clone(), topic));
+                    }
+                }
+            }
+        }
+        for (sub_id, topic) in orphaned {
+            self.leave_topic(sub_id.as_str(), topic.as_str());
+        }
+        // committed offsets stay in offset_idx, only the live session state is dropped;
+        // unacked messages are behind the committed offset, the next session gets them again
+        for sub_id in left {
+            self.members.remove(&sub_id);
+            self.member_cursor.remove(&sub_id);
+            self.connection_offset.remove(&sub_id);
+            self.connection_topics.remove(&sub_id);
+            self.filters.remove(&sub_id);
+            self.member_topics.remove(&sub_id);
+            self.ack_subs.remove(&sub_id);
+            self.in_flight.remove(&sub_id);
+            self.stalled.retain(|(s, _)| *s != sub_id);
+        }
+        self.credit_window.remove(client_id);
+        self.redeliver_expired();
+        println!("Client:{} Unsubscribe Successful", msg.client_id);
+    }
+}
+
+impl Handler<UnsubscribeCmd> for ConsumerActor {
+    type Result = ();
+    fn handle(&mut self, msg: UnsubscribeCmd, _ctx: &mut Self::Context) {
+        let client_id = msg.client_id.as_str();
+        let now = Instant::now();
+        for sub_id in self.subscriptions_of(client_id) {
+            let Some(member) = self.member_topics.get_mut(&sub_id).and_then(|m| m.get_mut(client_id)) else {
+                continue;
+            };
+            let mut dropped: Vec<String> = vec![];
+            for topic in msg.topics.iter() {
+                if !is_pattern(topic) {
+                    member.named.retain(|t| t != topic);
+                    // a pattern still matching the topic must not bring it back
+                    if member.patterns.iter().any(|p| topic_matches(p, topic)) && !member.excluded.contains(topic) {
+                        member.excluded.push(topic.clone());
+                    }
+                    dropped.push(topic.clone());
+                    continue;
+                }
+                if !member.patterns.contains(topic) {
+                    continue;
+                }
+                member.patterns.retain(|p| p != topic);
+                // the topics the pattern matched, unless subscribed by name or matched by another pattern
+                for matched in member.topics.iter() {
+                    if topic_matches(topic, matched)
+                        && !member.named.contains(matched)
+                        && !member.patterns.iter().any(|p| topic_matches(p, matched)) {
+                        dropped.push(matched.clone());
+                    }
+                }
+                let patterns = &member.patterns;
+                member.excluded.retain(|t| patterns.iter().any(|p| topic_matches(p, t)));
+            }
+            member.topics.retain(|t| !dropped.contains(t));
+            for topic in dropped {
+                let wanted = self.member_topics
+                    .get(&sub_id)
+                    .is_some_and(|members| members.values().any(|m| m.topics.contains(&topic)));
+                if wanted {
+                    // other members of the group keep the topic, they take over the unacked messages
+                    for flight in self.in_flight.get_mut(&sub_id).into_iter().flat_map(|f| f.values_mut()) {
+                        if flight.client_id == client_id && flight.topic == topic {
+                            flight.client_id = String::new();
+                            flight.deadline = now;
+                        }
+                    }
+                } else if self.connection_topics.get(&sub_id).is_some_and(|tps| tps.contains(&topic)) {
+                    self.leave_topic(sub_id.as_str(), topic.as_str());
+                }
+                println!("consumer:{client_id} unsubscribe {topic} of {sub_id}");
+            }
+            // drop the pattern filters no member uses any more
+            let patterns: Vec<String> = self.member_topics
+                .get(&sub_id)
+                .map(|members| members.values().flat_map(|m| m.patterns.clone()).collect())
+                .unwrap_or_default();
+            if let Some(filters) = self.filters.get_mut(&sub_id) {
+                filters.retain(|t, _| !is_pattern(t) || patterns.contains(t));
+            }
+        }
+        self.redeliver_expired();
+    }
+}
+
+impl Handler<AckCmd> for ConsumerActor {
+    type Result = ();
+    fn handle(&mut self, msg: AckCmd, _ctx: &mut Self::Context) {
+        let mut acked: Vec<(String, String)> = vec![];
+        for sub_id in self.subscriptions_of(msg.client_id.as_str()) {
+            if let Some(flights) = self.in_flight.get_mut(&sub_id) {
+                for nonce in msg.nonces.iter() {
+                    if let Some(flight) = flights.remove(nonce) {
+                        if !acked.iter().any(|(s, t)| *s == sub_id && *t == flight.topic) {
+                            acked.push((sub_id.clone(), flight.topic));
+                        }
+                    }
+                }
+            }
+        }
+        let now = Instant::now();
+        for (sub_id, topic) in acked {
+            self.commit_offset(sub_id.as_str(), topic.as_str());
+            // the acked messages freed room in the credit window
+            self.deliver_topic(sub_id.as_str(), topic.as_str(), now);
+        }
+    }
+}
+
+impl Handler<NackCmd> for ConsumerActor {
+    type Result = ();
+    fn handle(&mut self, msg: NackCmd, _ctx: &mut Self::Context) {
+        let now = Instant::now();
+        let mut matched = false;
+        for sub_id in self.subscriptions_of(msg.client_id.as_str()) {
+            if let Some(flights) = self.in_flight.get_mut(&sub_id) {
+                for nonce in msg.nonces.iter() {
+                    if let Some(flight) = flights.get_mut(nonce) {
+                        flight.deadline = now;
+                        matched = true;
+                    }
+                }
+            }
+        }
+        if matched {
+            self.redeliver_expired();
+        }
+    }
+}
+
+impl Handler<AttachDispacherCmd> for ConsumerActor {
+    type Result = ();
+    fn handle(&mut self, msg: AttachDispacherCmd, _ctx: &mut Self::Context) {
+        self.dispacher = Some(msg.0);
+    }
+}
+
+impl Handler<SubscribedTopicsCmd> for ConsumerActor {
+    type Result = MessageResult<SubscribedTopicsCmd>;
+
+    fn handle(&mut self, _msg: SubscribedTopicsCmd, _ctx: &mut Self::Context) -> Self::Result {
+        let mut topics: Vec<String> = self.connection_topics.values().flatten().cloned().collect();
+        topics.sort();
+        topics.dedup();
+        MessageResult(topics)
+    }
+}
+
+impl Handler<PatternsCmd> for ConsumerActor {
+    type Result = MessageResult<PatternsCmd>;
+
+    fn handle(&mut self, _msg: PatternsCmd, _ctx: &mut Self::Context) -> Self::Result {
+        let mut subs: Vec<(HandoverMember, String)> = vec![];
+        for (sub_id, members) in self.member_topics.iter() {
+            for (client_id, member) in members.iter() {
+                for pattern in member.patterns.iter() {
+                    let Some(addr) = self.connection_addr.get(client_id) else {
+                        continue;
+                    };
+                    let member = HandoverMember {
+                        group: sub_group(sub_id.as_str()).map(str::to_string),
+                        addr: addr.clone(),
+                        filter: self.filters.get(sub_id).and_then(|f| f.get(pattern)).cloned(),
+                        // the dispacher splits the client's default window over the new partitions
+                        credit: None,
+                        ack: self.ack_subs.contains(sub_id),
+                        client_id: client_id.clone()
+                    };
+                    subs.push((member, pattern.clone()));
+                }
+            }
+        }
+        MessageResult(subs)
+    }
+}
+
+impl Handler<HandoverCmd> for ConsumerActor {
+    type Result = MessageResult<HandoverCmd>;
+
+    fn handle(&mut self, msg: HandoverCmd, _ctx: &mut Self::Context) -> Self::Result {
+        let topic = msg.topic.as_str();
+        let mut handover = Handover::default();
+        let subs: Vec<String> = self.connection_topics
+            .iter()
+            .filter(|(_, topics)| topics.iter().any(|t| t == topic))
+            .map(|(sub_id, _)| sub_id.clone())
+            .collect();
+        for sub_id in subs {
+            let clients = self.members_for(sub_id.as_str(), topic);
+            // unacked messages are delivered again by the new partition
+            let filter = self.leave_topic(sub_id.as_str(), topic);
+            for client_id in clients {
+                let Some(addr) = self.connection_addr.get(&client_id) else {
+                    continue;
+                };
+                handover.members.push(HandoverMember {
+                    group: sub_group(sub_id.as_str()).map(str::to_string),
+                    addr: addr.clone(),
+                    filter: filter.clone(),
+                    // only a window of its own follows the topic, the default one is split per partition
+                    credit: self.credit_window.get(&client_id).and_then(|w| w.topics.get(topic).copied()),
+                    ack: self.ack_subs.contains(&sub_id),
+                    client_id
+                });
+            }
+        }
+        let mut suffix = vec![0];
+        suffix.extend_from_slice(topic.as_bytes());
+        for (key, offset) in self.offset_idx.iter().flatten() {
+            if !key.ends_with(&suffix) {
+                continue;
+            }
+            let sub_id = String::from_utf8_lossy(&key[..key.len() - suffix.len()]).to_string();
+            handover.offsets.push((sub_id, vectu64(offset.to_vec())));
+            if let Err(err) = self.offset_idx.remove(key) {
+                eprintln!("remove handed over offset with err:{err}");
+            }
+        }
+        println!("hand over {topic}: {} members, {} offsets", handover.members.len(), handover.offsets.len());
+        MessageResult(handover)
+    }
+}
+
+impl Handler<ImportOffsetsCmd> for ConsumerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ImportOffsetsCmd, _ctx: &mut Self::Context) -> Self::Result {
+        for (sub_id, offset) in msg.offsets {
+            if let Err(err) = self.offset_idx.insert(make_offset_key(sub_id.as_str(), msg.topic.as_str()), &offset.to_be_bytes()) {
+                eprintln!("import offset of {sub_id} on {} with err:{err}", msg.topic);
+            }
         }
-        if self.connection_offset.contains_key(msg.client_id.as_str()){
-            self.connection_offset.remove(msg.client_id.as_str());
-            ct+=1;
+        // patterns start the topic from the offsets the old partition committed
+        if self.arriving.remove(&msg.topic) {
+            self.match_patterns(msg.topic.as_str());
+            let now = Instant::now();
+            let subscribers: Vec<String> = self.connection_topics
+                .iter()
+                .filter(|(_, topics)| topics.contains(&msg.topic))
+                .map(|(sub_id, _)| sub_id.clone())
+                .collect();
+            for sub_id in subscribers {
+                self.deliver_topic(sub_id.as_str(), msg.topic.as_str(), now);
+            }
         }
-        if self.connection_topics.contains_key(msg.client_id.as_str()){
-            self.connection_topics.remove(msg.client_id.as_str());
-            ct+=1;
+    }
+}
+
+impl Handler<ArrivingTopicCmd> for ConsumerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ArrivingTopicCmd, _ctx: &mut Self::Context) -> Self::Result {
+        self.arriving.insert(msg.topic);
+    }
+}
+
+impl Handler<CreditCmd> for ConsumerActor {
+    type Result = ();
+    fn handle(&mut self, msg: CreditCmd, _ctx: &mut Self::Context) {
+        let window = self.credit_window.entry(msg.client_id.clone()).or_default();
+        if msg.topics.is_empty() {
+            window.default = Some(msg.credit);
+            window.topics.clear();
+        } else {
+            for topic in msg.topics {
+                window.topics.insert(topic, msg.credit);
+            }
         }
-        //self.connection_count = self.connection_count - 1;
-        if ct > 2{
-            println!("Client:{} Unsubscribe Successful", msg.client_id);
+        let now = Instant::now();
+        for sub_id in self.subscriptions_of(msg.client_id.as_str()) {
+            let topics = self.connection_topics.get(&sub_id).cloned().unwrap_or_default();
+            for topic in topics {
+                self.deliver_topic(sub_id.as_str(), topic.as_str(), now);
+            }
         }
     }
 }
@@ -94,8 +658,16 @@ impl Actor for ConsumerActor {
     fn started(&mut self, ctx: &mut Self::Context) {
         // println!("consumer started");
         ctx.set_mailbox_capacity(65536);
-        ctx.run_later(Duration::from_millis(10), |act, ctx|{
-            act.process_message(ctx);
+        // new messages are pushed by NewMessageCmd, the timer only watches ack deadlines
+        ctx.run_interval(Duration::from_millis(REDELIVER_INTERVAL_MS), |act, _ctx|{
+            if !act.in_flight.is_empty() {
+                act.redeliver_expired();
+            }
+            // retry the deliveries a full session mailbox refused
+            let now = Instant::now();
+            for (sub_id, topic) in std::mem::take(&mut act.stalled) {
+                act.deliver_topic(sub_id.as_str(), topic.as_str(), now);
+            }
         });
     }
     fn stopping(&mut self, _ctx: &mut Self::Context) -> actix::Running {
@@ -103,7 +675,9 @@ impl Actor for ConsumerActor {
         actix::Running::Stop
     }
 
-    fn stopped(&mut self, _ctx: &mut Self::Context) {}
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.offset_idx.flush().unwrap();
+    }
 
     fn start(self) -> Addr<Self>
     where
@@ -124,101 +698,377 @@ impl Actor for ConsumerActor {
 }
 
 
+/// how often unacked messages are checked against their deadline
+const REDELIVER_INTERVAL_MS: u64 = 100;
+
+/// Member of a subscription that gets the next message: the same key always
+/// lands on the same member, messages without key go round-robin
+fn pick_member(members: &[String], cursor: &mut usize, key: Option<&str>) -> Option<String> {
+    match members.len() {
+        0 => None,
+        1 => Some(members[0].clone()),
+        n => {
+            let idx = match key {
+                Some(key) => {
+                    let mut state = DefaultHasher::new();
+                    key.hash(&mut state);
+                    (state.finish() % n as u64) as usize
+                },
+                None => {
+                    *cursor = (*cursor + 1) % n;
+                    *cursor
+                }
+            };
+            Some(members[idx].clone())
+        }
+    }
+}
+
+
 impl ConsumerActor {
-    fn process_message(&mut self, ctx: &mut <ConsumerActor as Actor>::Context) {
-        let mut all_count = 0;
-        if self.connection_topics.len() < 1 {
-            ctx.run_later(Duration::from_millis(500), |act, ctx|{
-                act.process_message(ctx); 
-            });
+    /// Subscribe the members whose patterns match `topic` to it, the first message of a
+    /// new topic is how they learn about it; the subscription starts from its committed offset
+    fn match_patterns(&mut self, topic: &str) {
+        if self.arriving.contains(topic) {
             return;
         }
-        
-        for (cid, ofs) in self.connection_offset.iter_mut() {
-            let mut offset = *ofs;
-            let mut msg_count = 0;
-            if let Some(topics) = self.connection_topics.get(cid){
-                for topic in topics {
-                    let fetch_flag_min = make_key(topic.as_str(), offset);
-                    let fetch_flag_max = make_key(topic.as_str(), u64::MAX);
-                    // println!("fetch topic:{} from {}", topic, offset);
-                    let mut last_key = IVec::from("");
-                    let mut rest_count = 0;
-                    for item in self.main_idx.range(fetch_flag_min..fetch_flag_max){
-                        if let Ok((_k, data_key)) = item {
-                            let k4 = data_key.clone();
-                            match self.db.get(data_key){
-                                Ok(Some(data))=>{
-                                    if let Ok(json_text) = String::from_utf8(data.to_vec()) {
-                                        //let the_msg: Message = from_str(json_text.as_str()).unwrap();
-                                        if let Some(addr) = self.connection_addr.get(cid){
-                                            match addr.try_send(InnerMessage(json_text)) {
-                                                Ok(())=>{
-                                                    last_key = k4.clone();
-                                                    rest_count = rest_count + 1;
-                                                    all_count = all_count + 1;
-                                                    msg_count = msg_count + 1;
-                                                    // println!("{topic}=>{:?}", the_msg.key);
-                                                },
-                                                Err(err)=>{
-                                                    println!("dispatch message to {} with err:{}", cid, err);
-                                                }
-                                            }
-                                        }else{
-                                            println!("can not get addr:{cid} msg:{json_text}");
-                                        }
-                                    } else {
-                                        println!("invalid json");
-                                    }
-                                },
-                                Ok(None)=>{
-                                    println!("get data None with key:{}", String::from_utf8(k4.to_vec()).unwrap());
-                                },
-                                Err(err)=>{
-                                    println!("get data with err:{}", err);
-                                }
-                            }
-                        }
-                    }//---循环获取消息
-                    if rest_count > 0 {
-                        let k3 = last_key.clone();
-                        //println!("got {rest_count} msg, last key:{:?}", String::from_utf8(k3.clone().to_vec()));
-                        match self.nonce_idx.get(last_key){
-                            Ok(Some(nonce_ivec))=>{
-                                let new_offset = vectu64(nonce_ivec.to_vec());
-                                if new_offset > offset{
-                                    offset = new_offset;
-                                    //println!("processed {} record in topic:{} set offset t0:{}", rest_count, topic, offset);
-                                }
-                            },
-                            Ok(None)=>{
-                                eprintln!("can not get nonce with key:{:?}", k3);
-                            }
-                            Err(err)=>{
-                                eprintln!("get nonce error:{}", err);
-                            }
-                        }
-                    }else{
-                        //println!("no more message on {topic} offset:{offset}");
-                    }
+        let mut matched: Vec<(String, String, String)> = vec![];
+        for (sub_id, members) in self.member_topics.iter() {
+            for (client_id, member) in members.iter() {
+                if member.topics.iter().any(|t| t == topic) || member.excluded.iter().any(|t| t == topic) {
+                    continue;
                 }
+                if let Some(pattern) = member.patterns.iter().find(|p| topic_matches(p, topic)) {
+                    matched.push((sub_id.clone(), client_id.clone(), pattern.clone()));
+                }
+            }
+        }
+        for (sub_id, client_id, pattern) in matched {
+            if let Some(member) = self.member_topics.get_mut(&sub_id).and_then(|m| m.get_mut(&client_id)) {
+                member.topics.push(topic.to_string());
+            }
+            if self.connection_topics.get(&sub_id).is_some_and(|topics| topics.iter().any(|t| t == topic)) {
+                continue;
             }
-            if msg_count > 0{
-                *ofs = offset+1;
-            }else{
-                //println!("{cid} have no more message");
+            let offset = self.committed_offset(sub_id.as_str(), topic);
+            println!("consumer:{sub_id} start {topic} from {offset}, matched by {pattern}");
+            if let Some(filter) = self.filters.get(&sub_id).and_then(|f| f.get(&pattern)).cloned() {
+                self.filters.entry(sub_id.clone()).or_default().insert(topic.to_string(), filter);
+            }
+            self.connection_offset.entry(sub_id.clone()).or_default().insert(topic.to_string(), offset);
+            self.connection_topics.entry(sub_id).or_default().insert(0, topic.to_string());
+        }
+    }
+
+    /// Last offset committed by `sub_id` on `topic`, 0 if it never consumed it
+    fn committed_offset(&self, sub_id: &str, topic: &str) -> u64 {
+        match self.offset_idx.get(make_offset_key(sub_id, topic)) {
+            Ok(Some(v)) => vectu64(v.to_vec()),
+            Ok(None) => 0,
+            Err(err) => {
+                eprintln!("read offset of {sub_id} on {topic} with err:{err}");
+                0
             }
         }
-        
-        if all_count > 0{
-            ctx.run_later(Duration::from_millis(100), |act, ctx|{
-                act.process_message(ctx); 
-            });
-        }else{
-            ctx.run_later(Duration::from_millis(200), |act, ctx|{
-                act.process_message(ctx); 
-            });
+    }
+
+    /// First nonce of `topic` published at or after `since`,
+    /// past the newest message when everything is older
+    fn offset_since(&self, topic: &str, since: i64) -> u64 {
+        let first = self.time_idx
+            .range(i64to_vec(since)..)
+            .flatten()
+            .find(|(_, t)| t.as_ref() == topic.as_bytes());
+        if let Some((time_key, _)) = first {
+            return key_nonce(&time_key);
+        }
+        match self.main_idx.range(topic_range(topic, 0)).next_back() {
+            Some(Ok((key, _))) => key_nonce(&key) + 1,
+            _ => 0
+        }
+    }
+
+    /// Move the committed offset of `topic` up to the oldest unacked message,
+    /// or to the delivery cursor when nothing is in flight
+    fn commit_offset(&mut self, sub_id: &str, topic: &str) {
+        let oldest_unacked = self.in_flight.get(sub_id).and_then(|flights| {
+            flights.iter().find(|(_, f)| f.topic == topic).map(|(nonce, _)| *nonce)
+        });
+        let cursor = self.connection_offset.get(sub_id).and_then(|offsets| offsets.get(topic)).copied();
+        let Some(committed) = oldest_unacked.or(cursor) else {
+            return;
+        };
+        if let Err(err) = self.offset_idx.insert(make_offset_key(sub_id, topic), &committed.to_be_bytes()) {
+            eprintln!("commit offset of {sub_id} on {topic} with err:{err}");
+        }
+    }
+
+    /// Stop delivering `topic` to `sub_id`, the offset is committed so a later subscription resumes there,
+    /// returns the filter the subscription had on it
+    fn leave_topic(&mut self, sub_id: &str, topic: &str) -> Option<HashMap<String, String>> {
+        self.commit_offset(sub_id, topic);
+        if let Some(topics) = self.connection_topics.get_mut(sub_id) {
+            topics.retain(|t| t != topic);
+        }
+        if let Some(offsets) = self.connection_offset.get_mut(sub_id) {
+            offsets.remove(topic);
         }
+        if let Some(flights) = self.in_flight.get_mut(sub_id) {
+            flights.retain(|_, f| f.topic != topic);
+        }
+        self.stalled.remove(&(sub_id.to_string(), topic.to_string()));
+        for member in self.member_topics.get_mut(sub_id).into_iter().flat_map(|m| m.values_mut()) {
+            member.topics.retain(|t| t != topic);
+        }
+        self.filters.get_mut(sub_id).and_then(|f| f.remove(topic))
+    }
+
+    /// Members of `sub_id` that subscribed to `topic`
+    fn members_for(&self, sub_id: &str, topic: &str) -> Vec<String> {
+        let Some(members) = self.members.get(sub_id) else {
+            return vec![];
+        };
+        let subscribed = self.member_topics.get(sub_id);
+        members
+            .iter()
+            .filter(|m| subscribed.and_then(|s| s.get(*m)).is_some_and(|mt| mt.topics.iter().any(|t| t == topic)))
+            .cloned()
+            .collect()
+    }
+
+    /// Subscriptions `client_id` receives messages for
+    fn subscriptions_of(&self, client_id: &str) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|(_, members)| members.iter().any(|m| m == client_id))
+            .map(|(sub_id, _)| sub_id.clone())
+            .collect()
+    }
+
+    /// Raw message stored under `topic` and `nonce`
+    fn load_message(&self, topic: &str, nonce: u64) -> Option<Vec<u8>> {
+        let data_key = self.main_idx.get(make_key(topic, nonce)).ok()??;
+        let data = self.db.get(data_key).ok()??;
+        Some(data.to_vec())
+    }
+
+    /// Whether the stored message carries every header the subscription filters on
+    fn passes_filter(&self, sub_id: &str, topic: &str, data: &[u8]) -> bool {
+        let Some(filter) = self.filters.get(sub_id).and_then(|f| f.get(topic)) else {
+            return true;
+        };
+        let headers = Message::from_stored(data).ok().and_then(|m| m.headers).unwrap_or_default();
+        filter.iter().all(|(name, value)| headers.get(name) == Some(value))
+    }
+
+    /// Whether `client_id` may get one more message of `topic` within its credit window;
+    /// a topic's own window counts its unacked messages, the default one those of every
+    /// topic without a window of its own, over all subscriptions of the client
+    fn has_credit(&self, client_id: &str, topic: &str) -> bool {
+        let Some(window) = self.credit_window.get(client_id) else {
+            return true;
+        };
+        let Some(credit) = window.of(topic) else {
+            return true;
+        };
+        let own = window.topics.contains_key(topic);
+        let counted = |t: &str| if own { t == topic } else { !window.topics.contains_key(t) };
+        let outstanding: usize = self.subscriptions_of(client_id)
+            .iter()
+            .filter_map(|sub_id| self.in_flight.get(sub_id))
+            .map(|flights| flights.values().filter(|f| f.client_id == client_id && counted(f.topic.as_str())).count())
+            .sum();
+        (outstanding as u64) < credit
     }
-    
-}
\ No newline at end of file
+
+    /// Member of `sub_id` subscribed to `topic` with credit left for the message, `None` makes the delivery wait
+    fn choose_member(&mut self, sub_id: &str, topic: &str, data: &[u8]) -> Option<String> {
+        let members = self.members_for(sub_id, topic);
+        let key = if members.len() > 1 {
+            Message::from_stored(data).ok().and_then(|m| m.key)
+        } else {
+            None
+        };
+        // a keyed message waits for its member so the key order is kept
+        let candidates: Vec<String> = if key.is_some() {
+            members
+        } else {
+            members.into_iter().filter(|m| self.has_credit(m, topic)).collect()
+        };
+        let cursor = self.member_cursor.entry(sub_id.to_string()).or_default();
+        let member = pick_member(&candidates, cursor, key.as_deref())?;
+        if self.has_credit(member.as_str(), topic) {
+            Some(member)
+        } else {
+            None
+        }
+    }
+
+    /// Re-publish a message that failed `failures` deliveries to `<topic>.dlq`,
+    /// false when it could not be queued
+    fn dead_letter(&mut self, topic: &str, nonce: u64, failures: u32, data: &[u8]) -> bool {
+        let Some(dispacher) = self.dispacher.as_mut() else {
+            return false;
+        };
+        let Ok(mut message) = Message::from_stored(data) else {
+            return false;
+        };
+        message.topic = Some(dlq_topic(topic));
+        message.origin_topic = Some(topic.to_string());
+        message.origin_nonce = Some(nonce);
+        message.failures = Some(failures);
+        // not queued: the message stays in flight and the next deadline tries again
+        if dispacher.dispach_message(&mut message).is_err() {
+            return false;
+        }
+        println!("dead letter {nonce} of {topic} after {failures} deliveries");
+        true
+    }
+
+    /// Send again every in-flight message whose ack deadline passed
+    fn redeliver_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let mut due: Vec<(String, u64, String, String, u32)> = vec![];
+        for (sub_id, flights) in self.in_flight.iter() {
+            for (nonce, flight) in flights.iter() {
+                if flight.deadline <= now {
+                    due.push((sub_id.clone(), *nonce, flight.topic.clone(), flight.client_id.clone(), flight.attempts));
+                }
+            }
+        }
+        let mut count = 0;
+        let mut settled: Vec<(String, String)> = vec![];
+        for (sub_id, nonce, topic, client_id, attempts) in due {
+            let data = self.load_message(topic.as_str(), nonce);
+            let max_deliveries = self.config.topic(topic.as_str()).max_deliveries;
+            // removed by trim, expired, or delivered too many times: nothing left to redeliver
+            let done = match &data {
+                None => true,
+                Some(data) if Message::from_stored(data).is_ok_and(|m| m.is_expired()) => true,
+                Some(data) if max_deliveries.is_some_and(|max| attempts >= max) => {
+                    self.dead_letter(topic.as_str(), nonce, attempts, data)
+                },
+                Some(_) => false
+            };
+            if done {
+                if let Some(flights) = self.in_flight.get_mut(&sub_id) {
+                    flights.remove(&nonce);
+                }
+                settled.push((sub_id, topic));
+                continue;
+            }
+            let Some(data) = data else {
+                continue;
+            };
+            let target = if self.connection_addr.contains_key(&client_id) {
+                Some(client_id)
+            } else {
+                let members = self.members_for(sub_id.as_str(), topic.as_str());
+                let key = Message::from_stored(&data).ok().and_then(|m| m.key);
+                pick_member(&members, self.member_cursor.entry(sub_id.clone()).or_default(), key.as_deref())
+            };
+            let Some(target) = target else {
+                continue;
+            };
+            let Some(addr) = self.connection_addr.get(&target) else {
+                continue;
+            };
+            match addr.try_send(InnerMessage(data)) {
+                Ok(())=>{
+                    if let Some(flight) = self.in_flight.get_mut(&sub_id).and_then(|f| f.get_mut(&nonce)) {
+                        flight.attempts += 1;
+                        flight.deadline = now + self.config.ack_timeout();
+                        println!("redeliver {nonce} to {target}, attempt {}", flight.attempts);
+                        flight.client_id = target;
+                    }
+                    count += 1;
+                },
+                Err(err)=>{
+                    println!("redeliver message to {} with err:{}", target, err);
+                }
+            }
+        }
+        for (sub_id, topic) in settled {
+            self.commit_offset(sub_id.as_str(), topic.as_str());
+            self.deliver_topic(sub_id.as_str(), topic.as_str(), now);
+        }
+        count
+    }
+
+    /// Push the messages of `topic` past the cursor of `sub_id` to its members,
+    /// stopping at the first one no member can take right now
+    fn deliver_topic(&mut self, sub_id: &str, topic: &str, now: Instant) -> usize {
+        let Some(ofs) = self.connection_offset.get(sub_id).and_then(|offsets| offsets.get(topic)).copied() else {
+            return 0;
+        };
+        // println!("fetch topic:{} from {}", topic, offset);
+        let main_idx = self.main_idx.clone();
+        let mut last_nonce = None;
+        let mut count = 0;
+        for (k, data_key) in main_idx.range(topic_range(topic, ofs)).flatten() {
+            let nonce = key_nonce(&k);
+            let k4 = data_key.clone();
+            let data = match self.db.get(data_key){
+                Ok(Some(data))=>data.to_vec(),
+                Ok(None)=>{
+                    println!("get data None with key:{}", String::from_utf8(k4.to_vec()).unwrap());
+                    last_nonce = Some(nonce);
+                    continue;
+                },
+                Err(err)=>{
+                    println!("get data with err:{}", err);
+                    break;
+                }
+            };
+            if !self.passes_filter(sub_id, topic, &data) {
+                // filtered out for this subscription, it counts as consumed
+                last_nonce = Some(nonce);
+                continue;
+            }
+            if Message::from_stored(&data).is_ok_and(|m| m.is_expired()) {
+                // waiting for the sweeper, consumers never see it
+                last_nonce = Some(nonce);
+                continue;
+            }
+            let Some(client_id) = self.choose_member(sub_id, topic, &data) else {
+                // credit window full, an ack or a new grant resumes from here
+                break;
+            };
+            let Some(addr) = self.connection_addr.get(&client_id) else {
+                println!("can not get addr:{client_id} msg:{nonce}");
+                break;
+            };
+            match addr.try_send(InnerMessage(data)) {
+                Ok(())=>{
+                    if self.ack_subs.contains(sub_id) {
+                        self.in_flight.entry(sub_id.to_string()).or_default().insert(nonce, InFlight {
+                            topic: topic.to_string(),
+                            client_id,
+                            deadline: now + self.config.ack_timeout(),
+                            attempts: 1
+                        });
+                    }
+                    last_nonce = Some(nonce);
+                    count += 1;
+                },
+                Err(err)=>{
+                    // keep the cursor on this message, the timer tries again
+                    println!("dispatch message to {} with err:{}", client_id, err);
+                    self.stalled.insert((sub_id.to_string(), topic.to_string()));
+                    break;
+                }
+            }
+        }//---循环获取消息
+        if let Some(nonce) = last_nonce {
+            if let Some(ofs) = self.connection_offset.get_mut(sub_id).and_then(|offsets| offsets.get_mut(topic)) {
+                *ofs = nonce + 1;
+            }
+            // in ack mode the committed offset only moves once the messages are acked
+            if !self.ack_subs.contains(sub_id) {
+                self.commit_offset(sub_id, topic);
+            }
+        }
+        count
+    }
+
+}
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+pub const DATA_DIR: &str = "data";
+const META_FILE: &str = "data/meta.json";
+
+/// Hash used to route a topic to its partition
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TopicHash {
+    /// std `DefaultHasher`, only kept for data directories created before the metadata existed
+    Default,
+    /// 64 bit FNV-1a, stable across toolchains
+    Fnv1a,
+}
+
+impl TopicHash {
+    pub fn hash(&self, topic: &str) -> u64 {
+        match self {
+            TopicHash::Default => {
+                let mut state = DefaultHasher::new();
+                topic.hash(&mut state);
+                state.finish()
+            }
+            TopicHash::Fnv1a => fnv1a(topic.as_bytes()),
+        }
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// What the data directory was written with, so a restart routes topics the same way
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataMeta {
+    pub hash: TopicHash,
+    /// partition count, grows when partitions are added
+    #[serde(default)]
+    pub partitions: Option<u16>,
+    /// set while topics are migrated to added partitions, a restart finishes the migration
+    #[serde(default)]
+    pub migrating: bool,
+    /// topic -> partition it is pinned to, a changed pin moves the topic on the next start
+    #[serde(default)]
+    pub pins: BTreeMap<String, u16>,
+}
+
+impl DataMeta {
+    /// Read `data/meta.json`, a directory without it gets the legacy hash when it already holds partitions
+    pub fn load() -> Result<DataMeta, String> {
+        if let Ok(text) = std::fs::read_to_string(META_FILE) {
+            return serde_json::from_str(text.as_str()).map_err(|err| format!("parse {META_FILE}:{err}"));
+        }
+        let legacy = Path::new(DATA_DIR)
+            .read_dir()
+            .map(|mut entries| entries.any(|e| e.is_ok_and(|e| e.file_name().to_string_lossy().starts_with("db_"))))
+            .unwrap_or(false);
+        let meta = DataMeta {
+            hash: if legacy { TopicHash::Default } else { TopicHash::Fnv1a },
+            partitions: None,
+            migrating: false,
+            pins: BTreeMap::new(),
+        };
+        meta.save()?;
+        Ok(meta)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        std::fs::create_dir_all(DATA_DIR).map_err(|err| format!("create {DATA_DIR}:{err}"))?;
+        let text = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(META_FILE, text).map_err(|err| format!("write {META_FILE}:{err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_known_values() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(TopicHash::Fnv1a.hash("a"), 0xaf63dc4c8601ec8c);
+    }
+}
@@ -1,4 +1,6 @@
 pub mod websocks;
 pub mod storage;
 pub mod consumer;
-pub mod partition;
\ No newline at end of file
+pub mod partition;
+pub mod config;
+pub mod meta;
\ No newline at end of file
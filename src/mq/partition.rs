@@ -1,95 +1,551 @@
 use actix::Addr;
-use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use serde::Serialize;
-use super::consumer::{ConsumerActor, RegisterCmd, ClearConnCmd};
-use super::storage::{StorageActor, StorageCmd, TrimCmd};
-use super::websocks::{WsSession, IdGenerator, Message};
+use std::time::Duration;
+use super::consumer::{ConsumerActor, RegisterCmd, SubscribeOpts, ClearConnCmd, UnsubscribeCmd, AckCmd, NackCmd, CreditCmd, AttachDispacherCmd, SubscribedTopicsCmd, HandoverCmd, Handover, HandoverMember, ImportOffsetsCmd, PatternsCmd, ArrivingTopicCmd};
+use super::config::{MqConfig, TopicConfig};
+use super::meta::{DataMeta, DATA_DIR};
+use super::storage::{StorageActor, StorageCmd, BatchStorageCmd, TrimCmd, RemoveCmd, TopicsCmd, TopicSnapshotCmd, DropTopicCmd, migrate_day_idx, migrate_main_idx, nonce_hwm, MAIN_IDX};
+use super::websocks::{WsSession, IdGenerator, Message, PublishResp, PartitionsResp, Receipt, topic_range, key_nonce, dlq_topic, now_ms, is_pattern};
 use actix::prelude::*;
-use std::collections::hash_map::DefaultHasher;
-use serde_json::to_string_pretty;
 
 
+/// how long a tombstone of a compacted topic stays for live consumers
+const TOMBSTONE_TTL_MS: u64 = 3_600_000;
+/// how often a switched topic checks for publishes still on their way to its old partition
+const SWITCH_POLL_MS: u64 = 5;
+
 #[derive(Serialize)]
 pub struct Status {
     pub retain_messages: usize,
     pub disk_size: u64,
-    pub last_nonce: u64
+    pub last_nonce: u64,
+    /// topics with a retention policy, with their current usage
+    pub retention: HashMap<String, RetentionStatus>
+}
+
+#[derive(Serialize)]
+pub struct RetentionStatus {
+    pub max_messages: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub messages: u64,
+    pub bytes: u64
+}
+
+/// Partitions and how topics are routed to them, shared by every clone of the dispacher
+pub struct Routing {
+    pub partitions: HashMap<u16, Partition>,
+    /// topic -> partition still serving it while it is migrated to another one
+    pub moving: HashMap<String, u16>,
+    /// topic -> publishes routed to the partition it is leaving that are not queued or stored yet
+    pub pending: HashMap<String, Arc<AtomicUsize>>,
+    /// set while partitions are added
+    pub rebalancing: bool,
+    /// topics moved by the latest migration
+    pub moved: Vec<String>,
+    /// why the latest migration left topics behind, they stay in `moving` until a retry moves them
+    pub error: Option<String>,
+    /// client_id -> credit granted without topics, split over the partitions
+    pub credits: HashMap<String, u64>,
+}
+
+/// Counts a publish to a moving topic until it is dropped
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 #[derive(Clone)]
 pub struct PartitionDispacher {
-    pub partitions: HashMap<u16, Partition>,
-    pub id_generator: IdGenerator
+    pub routing: Arc<RwLock<Routing>>,
+    pub id_generator: IdGenerator,
+    pub config: MqConfig,
+    /// topic hash and pins the data directory is routed with
+    pub meta: DataMeta
 }
 
 impl PartitionDispacher {
-    pub fn from_number(num: u16) -> Self {
+    pub fn from_number(num: u16, config: MqConfig, meta: DataMeta) -> Self {
+        for (topic, pidx) in meta.pins.iter().filter(|(_, pidx)| **pidx >= num) {
+            eprintln!("topic {topic} pinned to missing partition {pidx}, hashed instead");
+        }
         let id_generator = IdGenerator::new(0);
         let mut partitions: HashMap<u16, Partition> = HashMap::new();
-        let mut nonce_vec: Vec<u64> = vec![];
         for idx in 0..num {
-            let mut partition = Partition::from_idx(idx, id_generator.clone());
-            nonce_vec.insert(idx as usize, partition.last_nonce());
+            let mut partition = Partition::from_idx(idx, id_generator.clone(), &config);
+            // nonces are global, the generator resumes after the highest one of any partition
+            id_generator.init_with(partition.last_nonce());
             partitions.insert(idx, partition);
         }
-        let max_nonce = nonce_vec.iter().max().unwrap();
-        id_generator.init_with(*max_nonce);
-        PartitionDispacher{
-            partitions,
-            id_generator
+        let dispacher = PartitionDispacher{
+            routing: Arc::new(RwLock::new(Routing{
+                partitions,
+                moving: HashMap::new(),
+                pending: HashMap::new(),
+                rebalancing: false,
+                moved: vec![],
+                error: None,
+                credits: HashMap::new()
+            })),
+            id_generator,
+            config,
+            meta
+        };
+        for p in dispacher.all_partitions() {
+            p.consumer_addr.do_send(AttachDispacherCmd(dispacher.clone()));
+        }
+        dispacher
+    }
+
+    /// Partition `topic` lands on with `num` partitions: the pinned one, else the hashed one
+    pub fn target_partition(&self, topic: &str, num: u16) -> u16 {
+        if let Some(pidx) = self.meta.pins.get(topic).filter(|pidx| **pidx < num) {
+            return *pidx;
         }
+        (self.meta.hash.hash(topic) % num as u64) as u16
     }
+
+    /// Partition serving `topic` right now
     fn topic_for_partition(&mut self, topic: &str) -> u16 {
-        let mut state = DefaultHasher::new();
-        topic.hash(&mut state);
-        (state.finish() % self.partitions.len() as u64) as u16
+        let routing = self.routing.read().unwrap();
+        if let Some(pidx) = routing.moving.get(topic) {
+            return *pidx;
+        }
+        self.target_partition(topic, routing.partitions.len() as u16)
     }
-    
-    pub fn dispach_message(&mut self, message: &mut Message) {
-        if let Some(topic) = message.got_topic(){
-            let pidx = self.topic_for_partition(topic.as_str());
-            if let Some(p) = self.partitions.get_mut(&pidx){
-                p.dispach_message(message);
+
+    /// Partition a publish to `topic` goes to, counted until the guard is dropped while the topic is moving,
+    /// so the switch can wait for the publishes still routed to the old partition
+    fn route(&self, topic: &str) -> (u16, Option<InFlight>) {
+        let routing = self.routing.read().unwrap();
+        if let Some(pidx) = routing.moving.get(topic) {
+            return (*pidx, routing.pending.get(topic).map(InFlight::new));
+        }
+        (self.target_partition(topic, routing.partitions.len() as u16), None)
+    }
+
+    pub fn partition(&self, pidx: u16) -> Option<Partition> {
+        self.routing.read().unwrap().partitions.get(&pidx).cloned()
+    }
+
+    /// Every partition, ordered by index
+    pub fn all_partitions(&self) -> Vec<Partition> {
+        let mut partitions: Vec<Partition> = self.routing.read().unwrap().partitions.values().cloned().collect();
+        partitions.sort_by_key(|p| p.idx);
+        partitions
+    }
+
+    /// Grow to `count` partitions and migrate the topics hashed to a new one in the background,
+    /// `partitions_status` reports how far it got. A moved topic is served by its old partition
+    /// until its messages are copied, then its subscriptions are handed over.
+    /// Asking for the current count again retries the topics a failed migration left behind.
+    pub fn add_partitions(&mut self, count: u16) -> Result<(), String> {
+        let old_count = {
+            let mut routing = self.routing.write().unwrap();
+            if routing.rebalancing {
+                return Err("Partitions are already being added".to_string());
+            }
+            let old_count = routing.partitions.len() as u16;
+            if count < old_count || (count == old_count && routing.moving.is_empty()) {
+                return Err(format!("Partition count {count} must be above {old_count}"));
             }
+            routing.rebalancing = true;
+            routing.moved.clear();
+            routing.error = None;
+            old_count
+        };
+        let mut dispacher = self.clone();
+        // not tied to the request, a client hanging up must not stop the migration halfway
+        actix::spawn(async move {
+            let rs = dispacher.rebalance(old_count, count).await;
+            let mut routing = dispacher.routing.write().unwrap();
+            if let Err(err) = rs {
+                eprintln!("add partitions {old_count} -> {count} with error:{err}");
+                routing.error = Some(err);
+            }
+            routing.rebalancing = false;
+        });
+        Ok(())
+    }
+
+    /// Partition count and the progress of the latest migration
+    pub fn partitions_status(&self) -> PartitionsResp {
+        let routing = self.routing.read().unwrap();
+        let mut moving: Vec<String> = routing.moving.keys().cloned().collect();
+        moving.sort();
+        PartitionsResp {
+            rs: routing.error.is_none(),
+            partitions: routing.partitions.len() as u16,
+            rebalancing: routing.rebalancing,
+            moving,
+            moved: routing.moved.clone(),
+            detail: routing.error.clone()
         }
     }
+
+    async fn rebalance(&mut self, old_count: u16, count: u16) -> Result<(), String> {
+        let mut moving: Vec<(String, u16, u16)> = vec![];
+        if old_count == count {
+            let routing = self.routing.read().unwrap();
+            for (topic, from) in routing.moving.iter() {
+                moving.push((topic.clone(), *from, self.target_partition(topic.as_str(), count)));
+            }
+        }
+        for p in self.all_partitions().into_iter().filter(|_| old_count < count) {
+            for topic in p.topics().await? {
+                let target = self.target_partition(topic.as_str(), count);
+                if target != p.idx {
+                    moving.push((topic, p.idx, target));
+                }
+            }
+        }
+        let patterns = match self.partition(0) {
+            Some(p) => p.patterns().await?,
+            None => vec![]
+        };
+        let mut added: Vec<Partition> = vec![];
+        for idx in old_count..count {
+            let partition = Partition::from_idx(idx, self.id_generator.clone(), &self.config);
+            partition.consumer_addr.do_send(AttachDispacherCmd(self.clone()));
+            added.push(partition);
+        }
+        {
+            let mut routing = self.routing.write().unwrap();
+            for (topic, from, _) in moving.iter() {
+                routing.moving.insert(topic.clone(), *from);
+                routing.pending.entry(topic.clone()).or_default();
+            }
+            for partition in added {
+                routing.partitions.insert(partition.idx, partition);
+            }
+        }
+        let credits: Vec<(String, u64)> = self.routing.read().unwrap().credits.clone().into_iter().collect();
+        for (client_id, credit) in credits {
+            self.split_credit(client_id.as_str(), credit);
+        }
+        // a restart in the middle of the migration opens every partition and finishes it
+        self.data_meta(count, true).save()?;
+        println!("partitions {old_count} -> {count}, moving {} topics", moving.len());
+
+        for (topic, from, to) in moving {
+            let rs = self.move_topic(topic.as_str(), from, to).await;
+            let mut routing = self.routing.write().unwrap();
+            match rs {
+                Ok(()) => routing.moved.push(topic),
+                Err(err) => {
+                    // the old partition keeps the topic and its subscribers until a retry moves it
+                    eprintln!("move topic {topic} from {from} to {to} with error:{err}");
+                    routing.error = Some(format!("Move {topic} from {from} to {to}:{err}"));
+                    routing.moving.insert(topic.clone(), from);
+                    routing.pending.entry(topic).or_default();
+                }
+            }
+        }
+        if self.routing.read().unwrap().moving.is_empty() {
+            self.data_meta(count, false).save()?;
+        }
+        // pattern subscriptions cover the new partitions once the moved topics are handed over,
+        // so a moved topic is not matched again from the start
+        for idx in old_count..count {
+            if let Some(p) = self.partition(idx) {
+                for (member, pattern) in patterns.iter() {
+                    p.take_over_pattern(member, pattern.as_str());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish a migration a restart interrupted, or one a changed pin or hash asked for:
+    /// move every topic that is not on the partition the current routing sends it to
+    pub async fn resume_migration(&mut self) -> Result<Vec<String>, String> {
+        let count = self.routing.read().unwrap().partitions.len() as u16;
+        let mut moved: Vec<String> = vec![];
+        for p in self.all_partitions() {
+            for topic in p.topics().await? {
+                let target = self.target_partition(topic.as_str(), count);
+                if target != p.idx {
+                    self.move_topic(topic.as_str(), p.idx, target).await?;
+                    moved.push(topic);
+                }
+            }
+        }
+        self.data_meta(count, false).save()?;
+        Ok(moved)
+    }
+
+    /// Metadata of the data directory routed over `count` partitions
+    fn data_meta(&self, count: u16, migrating: bool) -> DataMeta {
+        DataMeta{partitions: Some(count), migrating, ..self.meta.clone()}
+    }
+
+    /// Copy `topic` from partition `from` to `to`, switch its publishes over,
+    /// copy what was stored meanwhile and hand its subscriptions over
+    async fn move_topic(&mut self, topic: &str, from: u16, to: u16) -> Result<(), String> {
+        let (Some(old), Some(new)) = (self.partition(from), self.partition(to)) else {
+            return Err(format!("Missing partition {from} or {to}"));
+        };
+        // the target may already serve patterns matching the topic, they wait for the handed over offsets
+        new.consumer_addr.do_send(ArrivingTopicCmd{topic: topic.to_string()});
+        let copied = old.migrate_topic(&new, topic, 0, false).await?;
+        // new messages of the topic go to its new partition from now on
+        let pending = {
+            let mut routing = self.routing.write().unwrap();
+            routing.moving.remove(topic);
+            routing.pending.remove(topic)
+        };
+        // publishes routed before the switch are queued in the old storage before the tail is copied
+        while pending.as_ref().is_some_and(|count| count.load(Ordering::SeqCst) > 0) {
+            actix::clock::sleep(Duration::from_millis(SWITCH_POLL_MS)).await;
+        }
+        // delayed messages move with the tail, the old partition stops releasing them
+        let tail = old.migrate_topic(&new, topic, copied.map_or(0, |nonce| nonce + 1), true).await?;
+        let handover = old.handover(topic).await?;
+        new.take_over(topic, handover);
+        // the subscribers are on the new partition now, a copy left behind is dropped by the next migration
+        if let Err(err) = old.drop_topic(topic).await {
+            eprintln!("drop moved topic {topic} from {from} with error:{err}");
+        }
+        println!("topic {topic} moved from {from} to {to}, last nonce {:?}", tail.or(copied));
+        Ok(())
+    }
     
-    pub fn subscribe(&mut self, sock_addr:Addr<WsSession>, client_id: &str, topics: Vec<String>, offset: u64){
+    /// Queue `message` in its partition without waiting for it to be stored
+    pub fn dispach_message(&mut self, message: &mut Message) -> Result<(), String> {
+        let Some(topic) = message.got_topic() else {
+            return Err("Missing topic".to_string());
+        };
+        let (pidx, _in_flight) = self.route(topic.as_str());
+        match self.partition(pidx) {
+            Some(mut p) => p.dispach_message(message),
+            None => Err(format!("Missing partition {pidx}"))
+        }
+    }
+
+    /// Store `message` in its partition, returns where it was stored once it is durable
+    pub async fn publish(&mut self, message: &mut Message) -> Result<PublishResp, String> {
+        let Some(topic) = message.got_topic() else {
+            return Err("Missing topic".to_string());
+        };
+        let (pidx, _in_flight) = self.route(topic.as_str());
+        match self.partition(pidx) {
+            Some(mut p) => p.publish(message).await,
+            None => Err(format!("Missing partition {pidx}"))
+        }
+    }
+
+    /// Store a message the server publishes again, such as a redriven dead letter,
+    /// its uid is not deduplicated; returns once it is durable
+    pub async fn republish(&mut self, message: &mut Message) -> Result<PublishResp, String> {
+        let Some(topic) = message.got_topic() else {
+            return Err("Missing topic".to_string());
+        };
+        let (pidx, _in_flight) = self.route(topic.as_str());
+        match self.partition(pidx) {
+            Some(mut p) => p.store(message, false).await,
+            None => Err(format!("Missing partition {pidx}"))
+        }
+    }
+    
+    /// Store a batch grouped by partition, one storage operation per partition,
+    /// returns the receipts in the order of `messages`
+    pub async fn publish_batch(&mut self, messages: Vec<Message>) -> Result<Vec<Receipt>, String> {
+        let mut groups: HashMap<u16, (Partition, Vec<usize>, Vec<StorageCmd>)> = HashMap::new();
+        let count = messages.len();
+        let mut in_flight: Vec<InFlight> = vec![];
+        // each partition stores its part in batch order, so its nonces follow the batch
+        for (pos, mut message) in messages.into_iter().enumerate() {
+            let Some(topic) = message.got_topic() else {
+                return Err(format!("Missing topic in message {pos}"));
+            };
+            let (pidx, guard) = self.route(topic.as_str());
+            in_flight.extend(guard);
+            let group = match groups.entry(pidx) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match self.partition(pidx) {
+                    Some(p) => entry.insert((p, vec![], vec![])),
+                    None => return Err(format!("Missing partition {pidx}"))
+                }
+            };
+            let Some(cmd) = group.0.prepare_message(&mut message, true) else {
+                return Err(format!("Missing topic in message {pos}"));
+            };
+            group.1.push(pos);
+            group.2.push(cmd);
+        }
+        let mut receipts = vec![Receipt::Stored(0); count];
+        for (_, (mut p, positions, cmds)) in groups {
+            let stored = p.publish_batch(cmds).await?;
+            for (pos, receipt) in positions.into_iter().zip(stored) {
+                receipts[pos] = receipt;
+            }
+        }
+        Ok(receipts)
+    }
+
+    /// Subscribe literal topics on their partition and patterns on every partition,
+    /// the consumer of each partition matches a pattern against the topics it stores
+    pub fn subscribe(&mut self, sock_addr:Addr<WsSession>, client_id: &str, topics: Vec<String>, opts: SubscribeOpts){
+        let (patterns, topics): (Vec<String>, Vec<String>) = topics.into_iter().partition(|t| is_pattern(t));
+        if !patterns.is_empty() {
+            for mut p in self.all_partitions() {
+                p.subscribe(sock_addr.clone(), client_id, patterns.clone(), opts.clone());
+            }
+        }
         for topic in topics {
             let pidx = self.topic_for_partition(topic.as_str());
             println!("topic {topic} subscribe to {pidx}");
-            if let Some(p) = self.partitions.get_mut(&pidx){
-                p.subscribe(sock_addr.clone(), client_id, vec![topic], offset)
+            if let Some(mut p) = self.partition(pidx){
+                p.subscribe(sock_addr.clone(), client_id, vec![topic], opts.clone())
             }
         }
     }
     
     pub fn unsubscribe(&mut self, client_id: &str) {
-        for (_, p) in self.partitions.iter_mut() {
+        self.routing.write().unwrap().credits.remove(client_id);
+        for mut p in self.all_partitions() {
             p.unsubscribe(client_id);
         }
     }
     
-    pub fn trim_data(&mut self, days: u16) {
-        for (_, p) in self.partitions.iter_mut() {
-            p.trim_data(days);
+    /// Stop delivering `topics` to the client, patterns are dropped on every partition
+    pub fn unsubscribe_topics(&mut self, client_id: &str, topics: Vec<String>) {
+        let (patterns, topics): (Vec<String>, Vec<String>) = topics.into_iter().partition(|t| is_pattern(t));
+        if !patterns.is_empty() {
+            for mut p in self.all_partitions() {
+                p.unsubscribe_topics(client_id, patterns.clone());
+            }
+        }
+        for topic in topics {
+            let pidx = self.topic_for_partition(topic.as_str());
+            if let Some(mut p) = self.partition(pidx){
+                p.unsubscribe_topics(client_id, vec![topic]);
+            }
+        }
+    }
+
+    pub fn ack(&mut self, client_id: &str, nonces: Vec<u64>) {
+        // nonces are unique across partitions, the ones a partition did not deliver are ignored
+        for mut p in self.all_partitions() {
+            p.ack(client_id, nonces.clone());
+        }
+    }
+
+    pub fn nack(&mut self, client_id: &str, nonces: Vec<u64>) {
+        for mut p in self.all_partitions() {
+            p.nack(client_id, nonces.clone());
+        }
+    }
+
+    /// Limit the unacked messages of the client, a topic's credit goes to the partition serving it
+    /// and a credit without topics is split over the partitions
+    pub fn credit(&mut self, client_id: &str, topics: Vec<String>, credit: u64) -> Result<(), String> {
+        if topics.is_empty() {
+            let count = self.routing.read().unwrap().partitions.len() as u64;
+            if credit < count {
+                return Err(format!("Credit {credit} is below the {count} partitions it is split over, grant it per topic"));
+            }
+            self.routing.write().unwrap().credits.insert(client_id.to_string(), credit);
+            self.split_credit(client_id, credit);
+            return Ok(());
+        }
+        for topic in topics {
+            let pidx = self.topic_for_partition(topic.as_str());
+            if let Some(mut p) = self.partition(pidx){
+                p.credit(client_id, vec![topic], credit);
+            }
+        }
+        Ok(())
+    }
+
+    /// Give each partition its share of `credit`, at least one
+    fn split_credit(&mut self, client_id: &str, credit: u64) {
+        let partitions = self.all_partitions();
+        let count = partitions.len() as u64;
+        for (i, mut p) in partitions.into_iter().enumerate() {
+            let share = credit / count + u64::from((i as u64) < credit % count);
+            p.credit(client_id, vec![], share.max(1));
         }
     }
 
+    /// Publish the dead letters of `topic` to it again, returns how many were moved
+    pub async fn redrive(&mut self, topic: &str) -> usize {
+        let dlq = dlq_topic(topic);
+        let pidx = self.topic_for_partition(dlq.as_str());
+        let Some(p) = self.partition(pidx) else {
+            return 0;
+        };
+        let mut nonces: Vec<u64> = vec![];
+        for (nonce, mut message) in p.topic_messages(dlq.as_str()) {
+            message.topic = Some(topic.to_string());
+            message.origin_topic = None;
+            message.origin_nonce = None;
+            message.failures = None;
+            // a dead letter is only removed once its copy is stored
+            match self.republish(&mut message).await {
+                Ok(_) => nonces.push(nonce),
+                Err(err) => eprintln!("redrive {nonce} of {dlq} with error:{err}")
+            }
+        }
+        p.remove_messages(dlq.as_str(), nonces).await
+    }
+
+    /// Trim every partition, returns the removed message count per partition
+    pub async fn trim_data(&mut self, days: u16) -> HashMap<u16, usize> {
+        let before_ms = now_ms() - days as i64 * 86_400_000;
+        let mut removed = HashMap::new();
+        for mut p in self.all_partitions() {
+            removed.insert(p.idx, p.trim_data(before_ms).await);
+        }
+        removed
+    }
+
     pub fn sum_status(&mut self) -> Status {
         let mut disk_size = 0;
         let mut retain_messages = 0;
         let last_nonce = self.id_generator.get_max_id();
-        for (_, p) in self.partitions.iter_mut() {
+        for mut p in self.all_partitions() {
             let st = p.sum_status();
             disk_size+=st.disk_size;
             retain_messages+=st.retain_messages;
         }
+        let mut retention = HashMap::new();
+        let topics: Vec<(String, TopicConfig)> = self.config.topics
+            .iter()
+            .filter(|(_, tc)| tc.has_retention())
+            .map(|(topic, tc)| (topic.clone(), tc.clone()))
+            .collect();
+        for (topic, tc) in topics {
+            let pidx = self.topic_for_partition(topic.as_str());
+            let (messages, bytes) = match self.partition(pidx) {
+                Some(p) => p.topic_usage(topic.as_str()),
+                None => (0, 0)
+            };
+            retention.insert(topic, RetentionStatus{
+                max_messages: tc.max_messages,
+                max_bytes: tc.max_bytes,
+                messages,
+                bytes
+            });
+        }
         Status{
             disk_size,
             retain_messages,
-            last_nonce
+            last_nonce,
+            retention
         }
     }
 
@@ -101,88 +557,267 @@ pub struct Partition {
     pub idx: u16,
     pub db: sled::Db,
     pub r_idx: sled::Tree,
-    pub d_idx: sled::Tree,
     pub m_idx: sled::Tree,
-    pub nonce_idx: sled::Tree,
+    pub meta: sled::Tree,
     pub producer_addr: Addr<StorageActor>,
     pub consumer_addr: Addr<ConsumerActor>,
-    pub id_gen: IdGenerator
+    pub config: MqConfig
 }
 
 impl Partition {
-    fn from_idx(idx: u16, id_generator: IdGenerator) -> Self{
-        let db_file = format!("data/db_{idx}.sled");
+    fn from_idx(idx: u16, id_generator: IdGenerator, config: &MqConfig) -> Self{
+        let db_file = format!("{DATA_DIR}/db_{idx}.sled");
         let db = sled::open(db_file.as_str()).unwrap();
         let r_idx = db.open_tree("range_idx").unwrap();
-        let d_idx = db.open_tree("day_idx").unwrap();
-        let m_idx = db.open_tree("main_idx").unwrap();
+        let time_idx = db.open_tree("time_idx").unwrap();
+        let m_idx = db.open_tree(MAIN_IDX).unwrap();
         let nonce_idx = db.open_tree("uid_to_nonce_idx").unwrap();
-        
-        Partition {
-            idx,
+        let offset_idx = db.open_tree("consumer_offset_idx").unwrap();
+        let delay_idx = db.open_tree("delay_idx").unwrap();
+        let expire_idx = db.open_tree("expire_idx").unwrap();
+        let dedup_idx = db.open_tree("dedup_idx").unwrap();
+        let key_idx = db.open_tree("key_idx").unwrap();
+        let meta = db.open_tree("meta").unwrap();
+        migrate_day_idx(&db, &r_idx, &time_idx);
+        migrate_main_idx(&db, &m_idx);
+
+        let consumer_addr = ConsumerActor {
+            connection_offset: HashMap::new(),
+            connection_addr: HashMap::new(),
+            connection_count: 0,
+            connection_topics: HashMap::new(),
+            members: HashMap::new(),
+            member_cursor: HashMap::new(),
+            in_flight: HashMap::new(),
+            credit_window: HashMap::new(),
+            stalled: HashSet::new(),
+            filters: HashMap::new(),
+            member_topics: HashMap::new(),
+            ack_subs: HashSet::new(),
+            arriving: HashSet::new(),
+            config: config.clone(),
+            dispacher: None,
             db: db.clone(),
-            r_idx: r_idx.clone(),
-            d_idx: d_idx.clone(),
-            m_idx: m_idx.clone(),
-            nonce_idx: nonce_idx.clone(),
+            main_idx: m_idx.clone(),
+            time_idx: time_idx.clone(),
+            offset_idx,
+        }.start();
+        let producer_addr = StorageActor{
+            db: db.clone(),
+            range_idx: r_idx.clone(),
+            time_idx,
+            main_idx: m_idx.clone(),
+            nonce_idx,
+            delay_idx,
+            expire_idx,
+            dedup_idx,
+            key_idx,
+            meta: meta.clone(),
             id_gen: id_generator,
-            producer_addr: StorageActor{
-                db: db.clone(),
-                range_idx: r_idx.clone(),
-                day_idx: d_idx.clone(),
-                main_idx: m_idx.clone(),
-                nonce_idx: nonce_idx.clone()
-            }.start(),
-            consumer_addr: ConsumerActor {
-                connection_offset: HashMap::new(),
-                connection_addr: HashMap::new(),
-                connection_count: 0,
-                connection_topics: HashMap::new(),
-                db: db.clone(),
-                range_idx: r_idx.clone(),
-                day_idx: d_idx.clone(),
-                main_idx: m_idx.clone(),
-                nonce_idx: nonce_idx.clone(),
-            }.start()
+            config: config.clone(),
+            consumer_addr: consumer_addr.clone(),
+            notify: HashMap::new(),
+            leaving: HashSet::new()
+        }.start();
+
+        Partition {
+            idx,
+            db,
+            r_idx,
+            m_idx,
+            meta,
+            producer_addr,
+            consumer_addr,
+            config: config.clone()
         }
     }
 
-    pub fn last_nonce(&mut self) -> u64 {
-        if let Ok(Some((k, _v))) = self.r_idx.last() {
-            let last_id = u64::from_be_bytes(k.to_vec().try_into().unwrap());
-            println!("last id:{}", last_id);
-            last_id
-        }else{
-            0
-        }
-    }
-
-    pub fn dispach_message(&mut self, message: &mut Message) {
-        if let Some(topic) = message.got_topic() {
-            let key = format!("{}-{}", topic, message.uid);
-            let nonce = self.id_gen.gen_id();
-            message.set_nonce(nonce);
-            let cmd = StorageCmd{
-                st_key: key,
-                message_topic: topic.to_string(),
-                nonce: nonce,
-                data: to_string_pretty(message).unwrap()
-            };
-            match self.producer_addr.try_send(cmd) {
-                Ok(())=>{},
-                Err(err)=>{
-                    eprintln!("Dispatch message with error:{}", err);
-                }
+    /// Topics stored in or subscribed to on the partition
+    pub async fn topics(&self) -> Result<Vec<String>, String> {
+        let mut topics = self.producer_addr
+            .send(TopicsCmd)
+            .await
+            .map_err(|err| format!("Storage of partition {} unavailable:{err}", self.idx))?;
+        let subscribed = self.consumer_addr
+            .send(SubscribedTopicsCmd)
+            .await
+            .map_err(|err| format!("Consumer of partition {} unavailable:{err}", self.idx))?;
+        for topic in subscribed {
+            if !topics.contains(&topic) {
+                topics.push(topic);
             }
+        }
+        Ok(topics)
+    }
 
+    /// Copy the messages of `topic` from nonce `from` on into `target`, with its delayed ones when `delayed`,
+    /// returns the last copied nonce
+    async fn migrate_topic(&self, target: &Partition, topic: &str, from: u64, delayed: bool) -> Result<Option<u64>, String> {
+        let cmds = self.producer_addr
+            .send(TopicSnapshotCmd{topic: topic.to_string(), from, delayed})
+            .await
+            .map_err(|err| format!("Storage of partition {} unavailable:{err}", self.idx))?;
+        let last = cmds.iter().filter(|cmd| cmd.deliver_at.is_none()).filter_map(|cmd| cmd.nonce).max();
+        if !cmds.is_empty() {
+            target.producer_addr
+                .send(BatchStorageCmd{cmds})
+                .await
+                .map_err(|err| format!("Storage of partition {} unavailable:{err}", target.idx))??;
         }
+        Ok(last)
     }
-    
-    pub fn subscribe(&mut self, sock_addr:Addr<WsSession>, client_id: &str, topics: Vec<String>, offset: u64){
+
+    /// Stop serving `topic`, returns its subscriptions
+    async fn handover(&self, topic: &str) -> Result<Handover, String> {
+        self.consumer_addr
+            .send(HandoverCmd{topic: topic.to_string()})
+            .await
+            .map_err(|err| format!("Consumer of partition {} unavailable:{err}", self.idx))
+    }
+
+    /// Serve the subscriptions of `topic` handed over by another partition
+    fn take_over(&self, topic: &str, handover: Handover) {
+        self.consumer_addr.do_send(ImportOffsetsCmd{topic: topic.to_string(), offsets: handover.offsets});
+        for member in handover.members {
+            self.consumer_addr.do_send(RegisterCmd{
+                topics: vec![topic.to_string()],
+                client_id: member.client_id.clone(),
+                group: member.group,
+                offset: None,
+                since: None,
+                filter: member.filter,
+                ack: member.ack,
+                addr: member.addr
+            });
+            if let Some(credit) = member.credit {
+                self.consumer_addr.do_send(CreditCmd{
+                    client_id: member.client_id,
+                    topics: vec![topic.to_string()],
+                    credit
+                });
+            }
+        }
+    }
+
+    /// Pattern subscriptions of the partition with the member they deliver to
+    async fn patterns(&self) -> Result<Vec<(HandoverMember, String)>, String> {
+        self.consumer_addr
+            .send(PatternsCmd)
+            .await
+            .map_err(|err| format!("Consumer of partition {} unavailable:{err}", self.idx))
+    }
+
+    fn take_over_pattern(&self, member: &HandoverMember, pattern: &str) {
+        self.consumer_addr.do_send(RegisterCmd{
+            topics: vec![pattern.to_string()],
+            client_id: member.client_id.clone(),
+            group: member.group.clone(),
+            offset: None,
+            since: None,
+            filter: member.filter.clone(),
+            ack: member.ack,
+            addr: member.addr.clone()
+        });
+    }
+
+    async fn drop_topic(&self, topic: &str) -> Result<usize, String> {
+        self.producer_addr
+            .send(DropTopicCmd{topic: topic.to_string()})
+            .await
+            .map_err(|err| format!("Storage of partition {} unavailable:{err}", self.idx))
+    }
+
+    /// Highest nonce handed out to a message of the partition, delayed or trimmed ones included
+    pub fn last_nonce(&mut self) -> u64 {
+        let stored = match self.r_idx.last() {
+            Ok(Some((k, _v))) => u64::from_be_bytes(k.to_vec().try_into().unwrap()),
+            _ => 0
+        };
+        let delayed = self.db.open_tree("delay_idx")
+            .map(|delay_idx| delay_idx.iter().keys().flatten().map(|k| key_nonce(&k)).max().unwrap_or_default())
+            .unwrap_or_default();
+        let last_id = stored.max(delayed).max(nonce_hwm(&self.meta));
+        println!("last id:{}", last_id);
+        last_id
+    }
+
+    /// Assign timestamps and expiry to `message` and build its storage command,
+    /// the storage takes the nonce
+    fn prepare_message(&mut self, message: &mut Message, dedup: bool) -> Option<StorageCmd> {
+        let topic = message.got_topic()?;
+        let key = format!("{}-{}", topic, message.uid);
+        let deliver_at = message.got_deliver_at();
+        message.deliver_at = deliver_at;
+        let topic_config = self.config.topic(topic.as_str());
+        let tombstone = topic_config.compacted && message.is_tombstone();
+        let mut ttl_ms = message.ttl_ms.or(topic_config.ttl_ms);
+        if tombstone {
+            ttl_ms = Some(ttl_ms.unwrap_or(TOMBSTONE_TTL_MS).min(TOMBSTONE_TTL_MS));
+        }
+        let expire_at = ttl_ms.map(|ttl| deliver_at.unwrap_or_else(now_ms) + ttl as i64);
+        message.expire_at = expire_at;
+        let timestamp = now_ms();
+        message.timestamp = Some(timestamp);
+        Some(StorageCmd{
+            st_key: key,
+            message_topic: topic,
+            nonce: None,
+            deliver_at,
+            expire_at,
+            timestamp,
+            dedup,
+            key: message.key.clone(),
+            tombstone,
+            message: message.clone()
+        })
+    }
+
+    /// Store `message` without waiting for the result, used to republish
+    /// dead letters and redriven messages, so their uid is not deduplicated
+    pub fn dispach_message(&mut self, message: &mut Message) -> Result<(), String> {
+        let Some(cmd) = self.prepare_message(message, false) else {
+            return Err("Missing topic".to_string());
+        };
+        self.producer_addr.try_send(cmd).map_err(|err| {
+            eprintln!("Dispatch message with error:{}", err);
+            format!("Storage of partition {} unavailable:{err}", self.idx)
+        })
+    }
+
+    /// Store `message` and wait until it is on disk
+    pub async fn publish(&mut self, message: &mut Message) -> Result<PublishResp, String> {
+        self.store(message, true).await
+    }
+
+    async fn store(&mut self, message: &mut Message, dedup: bool) -> Result<PublishResp, String> {
+        let Some(cmd) = self.prepare_message(message, dedup) else {
+            return Err("Missing topic".to_string());
+        };
+        let key = cmd.st_key.clone();
+        let receipt = self.producer_addr
+            .send(cmd)
+            .await
+            .map_err(|err| format!("Storage of partition {} unavailable:{err}", self.idx))??;
+        Ok(PublishResp{rs:true, nonce:receipt.nonce(), delay_id:receipt.delay_id(), partition:self.idx, key})
+    }
+
+    /// Store prepared messages in one storage operation, returns their receipts in order
+    async fn publish_batch(&mut self, cmds: Vec<StorageCmd>) -> Result<Vec<Receipt>, String> {
+        self.producer_addr
+            .send(BatchStorageCmd{cmds})
+            .await
+            .map_err(|err| format!("Storage of partition {} unavailable:{err}", self.idx))?
+    }
+
+    pub fn subscribe(&mut self, sock_addr:Addr<WsSession>, client_id: &str, topics: Vec<String>, opts: SubscribeOpts){
         let cmd = RegisterCmd{
             topics: topics.clone(),
             client_id: client_id.to_string(),
-            offset,
+            group: opts.group,
+            offset: opts.offset,
+            since: opts.since,
+            filter: opts.filter,
+            ack: opts.ack,
             addr: sock_addr
         };
         match self.consumer_addr.try_send(cmd) {
@@ -209,30 +844,110 @@ impl Partition {
         }
     }
 
-    pub fn trim_data(&mut self, days: u16) {
-        let cmd = TrimCmd{days: days};
-        match self.producer_addr.try_send(cmd) {
-            Ok(())=>{
-                println!("trim data in segment {} success", self.idx);
+    pub fn unsubscribe_topics(&mut self, client_id: &str, topics: Vec<String>) {
+        let cmd = UnsubscribeCmd{
+            client_id: client_id.to_string(),
+            topics
+        };
+        if let Err(err) = self.consumer_addr.try_send(cmd) {
+            eprintln!("Unsubscribe topics with error:{}", err);
+        }
+    }
+
+    pub fn ack(&mut self, client_id: &str, nonces: Vec<u64>) {
+        let cmd = AckCmd{
+            client_id: client_id.to_string(),
+            nonces
+        };
+        if let Err(err) = self.consumer_addr.try_send(cmd) {
+            eprintln!("Ack with error:{}", err);
+        }
+    }
+
+    pub fn nack(&mut self, client_id: &str, nonces: Vec<u64>) {
+        let cmd = NackCmd{
+            client_id: client_id.to_string(),
+            nonces
+        };
+        if let Err(err) = self.consumer_addr.try_send(cmd) {
+            eprintln!("Nack with error:{}", err);
+        }
+    }
+
+    pub fn credit(&mut self, client_id: &str, topics: Vec<String>, credit: u64) {
+        let cmd = CreditCmd{
+            client_id: client_id.to_string(),
+            topics,
+            credit
+        };
+        if let Err(err) = self.consumer_addr.try_send(cmd) {
+            eprintln!("Credit with error:{}", err);
+        }
+    }
+
+    /// Every stored message of `topic` with its nonce, oldest first
+    pub fn topic_messages(&self, topic: &str) -> Vec<(u64, Message)> {
+        let mut messages: Vec<(u64, Message)> = vec![];
+        for (k, data_key) in self.m_idx.range(topic_range(topic, 0)).flatten() {
+            if let Ok(Some(data)) = self.db.get(data_key) {
+                match Message::from_stored(&data) {
+                    Ok(message) => messages.push((key_nonce(&k), message)),
+                    Err(err) => eprintln!("invalid message in {topic}:{err}")
+                }
+            }
+        }
+        messages
+    }
+
+    pub async fn remove_messages(&self, topic: &str, nonces: Vec<u64>) -> usize {
+        let cmd = RemoveCmd{
+            topic: topic.to_string(),
+            nonces
+        };
+        match self.producer_addr.send(cmd).await {
+            Ok(count) => count,
+            Err(err) => {
+                eprintln!("remove messages of {topic} with error:{err}");
+                0
+            }
+        }
+    }
+
+    pub async fn trim_data(&mut self, before_ms: i64) -> usize {
+        let cmd = TrimCmd{before_ms};
+        match self.producer_addr.send(cmd).await {
+            Ok(removed)=>{
+                println!("trim data in segment {} success, {removed} removed", self.idx);
+                removed
             },
             Err(err)=>{
-                eprintln!("trim data {days} days with error:{err}");
+                eprintln!("trim data before {before_ms} with error:{err}");
+                0
             }
         }
     }
 
     pub fn sum_status(&mut self) -> Status {
-        let disk_size = match self.db.size_on_disk(){
-            Ok(sz)=>sz,
-            Err(_)=>0
-        };
+        let disk_size = self.db.size_on_disk().unwrap_or_default();
 
         let retain_messages = self.r_idx.len();
         Status{
             retain_messages,
             disk_size,
-            last_nonce:0
+            last_nonce:0,
+            retention: HashMap::new()
+        }
+    }
+
+    /// Message count and stored bytes of `topic`
+    pub fn topic_usage(&self, topic: &str) -> (u64, u64) {
+        let mut messages = 0;
+        let mut bytes = 0;
+        for (_, data_key) in self.m_idx.range(topic_range(topic, 0)).flatten() {
+            messages += 1;
+            bytes += self.db.get(data_key).ok().flatten().map(|v| v.len() as u64).unwrap_or_default();
         }
+        (messages, bytes)
     }
 
 }
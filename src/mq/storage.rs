@@ -1,143 +1,780 @@
 use actix::{ Actor, Context, Handler};
-use actix::dev::{MessageResponse};
 use actix::prelude::*;
 use sled::IVec;
+use sled::Transactional;
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionError, TransactionResult, TransactionalTree, UnabortableTransactionError};
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
-use super::websocks::{i64to_vec, today_ts, make_key, vectu64};
+use super::websocks::{i64to_vec, now_ms, make_key, topic_range, key_topic, make_time_key, make_compact_key, key_nonce, vectu64, IdGenerator, Message, Receipt};
+use super::consumer::{ConsumerActor, NewMessageCmd};
+use super::config::MqConfig;
 
+/// store one message, answered with its receipt once it is flushed to disk
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<Receipt, String>")]
 pub struct StorageCmd {
     pub st_key: String,
     pub message_topic: String,
-    pub nonce: u64,
-    pub data: String
+    /// nonce, or delay id of a delayed message, kept when it is copied from another partition,
+    /// otherwise taken when it is stored
+    pub nonce: Option<u64>,
+    pub message: Message,
+    /// epoch ms the message becomes visible to consumers, `None` right away
+    pub deliver_at: Option<i64>,
+    /// epoch ms the message is dropped, `None` keeps it until trimmed
+    pub expire_at: Option<i64>,
+    /// server time of the publish in epoch ms, utc
+    pub timestamp: i64,
+    /// return the earlier receipt when the uid was already published within the dedup window
+    pub dedup: bool,
+    /// compaction key, only used on compacted topics
+    pub key: Option<String>,
+    /// null payload: removes the key of a compacted topic
+    pub tombstone: bool
 }
 
+/// store several messages of one partition with a single flush, answered with their receipts in order;
+/// stops at the first message that fails, the ones before it stay stored
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<Vec<Receipt>, String>")]
+pub struct BatchStorageCmd {
+    pub cmds: Vec<StorageCmd>
+}
+
+/// every topic holding messages in the partition, delayed ones included
+#[derive(Message)]
+#[rtype(result = "Vec<String>")]
+pub struct TopicsCmd;
+
+/// copies of the messages of `topic` from nonce `from` on, ready to be stored in another partition
+#[derive(Message)]
+#[rtype(result = "Vec<StorageCmd>")]
+pub struct TopicSnapshotCmd {
+    pub topic: String,
+    pub from: u64,
+    /// also copy the delayed messages, which are no longer released here afterwards
+    pub delayed: bool
+}
+
+/// drop every message of `topic`, delayed ones included, returns how many were removed
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct DropTopicCmd {
+    pub topic: String
+}
+
+/// drop everything stored before `before_ms` (epoch ms), returns how many messages were removed
+#[derive(Message)]
+#[rtype(result = "usize")]
 pub struct TrimCmd {
-    pub days: u16
+    pub before_ms: i64
 }
 
+/// drop the given messages of `topic` from every index
 #[derive(Message)]
-#[rtype(result = "()")]
-pub struct StatusCmd {
-    pub days: u16
+#[rtype(result = "usize")]
+pub struct RemoveCmd {
+    pub topic: String,
+    pub nonces: Vec<u64>
 }
 
 
+/// how often delayed messages are checked for being due
+const DELAY_CHECK_INTERVAL_MS: u64 = 100;
+/// how often expired messages are swept away
+const EXPIRE_SWEEP_INTERVAL_MS: u64 = 1000;
+/// how often the per topic retention policies are enforced
+const RETENTION_INTERVAL_MS: u64 = 10_000;
+/// how often uids older than the dedup window are forgotten
+const DEDUP_SWEEP_INTERVAL_MS: u64 = 60_000;
+/// meta tree key of the highest nonce ever stored in the partition
+const NONCE_HWM_KEY: &[u8] = b"nonce_hwm";
+/// main_idx written before topics in its keys were terminated
+pub const LEGACY_MAIN_IDX: &str = "main_idx";
+/// main_idx with keys built by `make_key`
+pub const MAIN_IDX: &str = "topic_idx";
+/// last byte of a dedup record that holds a delay id rather than a nonce
+const DELAYED_MARKER: u8 = 1;
 
 pub struct StorageActor{
     pub db: sled::Db,
     pub range_idx: sled::Tree,
-    pub day_idx: sled::Tree,
+    pub time_idx: sled::Tree,
     pub main_idx: sled::Tree,
     pub nonce_idx: sled::Tree,
+    pub delay_idx: sled::Tree,
+    pub expire_idx: sled::Tree,
+    pub dedup_idx: sled::Tree,
+    pub key_idx: sled::Tree,
+    ///  meta - partition wide values, the nonce high-water mark survives any trim
+    pub meta: sled::Tree,
+    pub id_gen: IdGenerator,
+    pub config: MqConfig,
+    /// woken up with the topic and latest nonce of the stored messages
+    pub consumer_addr: Addr<ConsumerActor>,
+    /// topic -> highest nonce stored since the consumer was last woken up
+    pub notify: HashMap<String, u64>,
+    /// topics whose delayed messages were copied to another partition, released there
+    pub leaving: HashSet<String>,
 }
 
 impl Actor for StorageActor {
     type Context = Context<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
         ctx.set_mailbox_capacity(65536);
+        ctx.run_interval(Duration::from_millis(DELAY_CHECK_INTERVAL_MS), |act, _ctx| {
+            act.release_due();
+        });
+        ctx.run_interval(Duration::from_millis(EXPIRE_SWEEP_INTERVAL_MS), |act, _ctx| {
+            act.sweep_expired();
+        });
+        ctx.run_interval(Duration::from_millis(RETENTION_INTERVAL_MS), |act, _ctx| {
+            act.enforce_retention();
+        });
+        ctx.run_interval(Duration::from_millis(DEDUP_SWEEP_INTERVAL_MS), |act, _ctx| {
+            act.prune_dedup();
+        });
     }
     
     fn stopped(&mut self, _ctx: &mut Self::Context) {
         self.db.flush().unwrap();
         self.range_idx.flush().unwrap();
-        self.day_idx.flush().unwrap();
+        self.time_idx.flush().unwrap();
         self.main_idx.flush().unwrap();
         self.nonce_idx.flush().unwrap();
+        self.delay_idx.flush().unwrap();
+        self.expire_idx.flush().unwrap();
+        self.dedup_idx.flush().unwrap();
+        self.key_idx.flush().unwrap();
+    }
+}
+
+impl StorageActor {
+    /// Remove the message stored under `topic` and `nonce` in one transaction, true when it existed
+    fn remove_message(&self, topic: &str, nonce: u64) -> bool {
+        let rs: TransactionResult<bool> = (&*self.db, &self.time_idx, &self.range_idx, &self.nonce_idx, &self.main_idx, &self.key_idx)
+            .transaction(|(db, time_idx, range_idx, nonce_idx, main_idx, key_idx)| {
+                let trees = MessageTrees { db, time_idx, range_idx, nonce_idx, main_idx, key_idx };
+                Ok(trees.remove(topic, nonce)?)
+            });
+        match rs {
+            Ok(removed) => removed,
+            Err(err) => {
+                eprintln!("remove {nonce} of {topic} with err:{err:?}");
+                false
+            }
+        }
     }
 }
 
+/// The trees holding a message, inside a transaction
+struct MessageTrees<'a> {
+    db: &'a TransactionalTree,
+    time_idx: &'a TransactionalTree,
+    range_idx: &'a TransactionalTree,
+    nonce_idx: &'a TransactionalTree,
+    main_idx: &'a TransactionalTree,
+    key_idx: &'a TransactionalTree,
+}
+
+impl MessageTrees<'_> {
+    /// Drop `nonce` of `topic` from every index, true when it existed
+    fn remove(&self, topic: &str, nonce: u64) -> Result<bool, UnabortableTransactionError> {
+        let Some(data_key) = self.main_idx.remove(make_key(topic, nonce))? else {
+            return Ok(false);
+        };
+        self.range_idx.remove(&nonce.to_be_bytes())?;
+        // the data key is shared with a later message of the same uid, keep its data
+        if self.nonce_idx.get(&data_key)?.is_some_and(|v| vectu64(v.to_vec()) == nonce) {
+            let message = self.db.get(&data_key)?.and_then(|data| Message::from_stored(&data).ok());
+            if let Some(timestamp) = message.as_ref().and_then(|m| m.timestamp) {
+                self.time_idx.remove(make_time_key(timestamp, nonce))?;
+            }
+            // a compacted key pointing at the removed message is gone with it
+            if let Some(key) = message.and_then(|m| m.key) {
+                let compact_key = make_compact_key(topic, key.as_str());
+                if self.key_idx.get(&compact_key)?.is_some_and(|v| vectu64(v.to_vec()) == nonce) {
+                    self.key_idx.remove(compact_key)?;
+                }
+            }
+            self.db.remove(&data_key)?;
+            self.nonce_idx.remove(&data_key)?;
+        }
+        Ok(true)
+    }
+}
+
+impl Handler<TopicsCmd> for StorageActor {
+    type Result = MessageResult<TopicsCmd>;
+    fn handle(&mut self, _msg: TopicsCmd, _ctx: &mut Self::Context) -> Self::Result {
+        let mut topics = stored_topics(&self.main_idx);
+        for (_, data_key) in self.delay_idx.iter().flatten() {
+            let topic = self.db.get(&data_key).ok().flatten()
+                .and_then(|data| Message::from_stored(&data).ok())
+                .and_then(|message| message.topic);
+            if let Some(topic) = topic.filter(|t| !topics.contains(t)) {
+                topics.push(topic);
+            }
+        }
+        MessageResult(topics)
+    }
+}
+
+impl Handler<TopicSnapshotCmd> for StorageActor {
+    type Result = MessageResult<TopicSnapshotCmd>;
+    fn handle(&mut self, msg: TopicSnapshotCmd, _ctx: &mut Self::Context) -> Self::Result {
+        let topic = msg.topic.as_str();
+        let mut cmds: Vec<StorageCmd> = vec![];
+        let copy = |data_key: IVec, nonce: u64, deliver_at: Option<i64>| -> Option<StorageCmd> {
+            let data = self.db.get(&data_key).ok()??;
+            let message = Message::from_stored(&data).ok()?;
+            Some(StorageCmd {
+                st_key: String::from_utf8(data_key.to_vec()).ok()?,
+                message_topic: message.topic.clone()?,
+                nonce: Some(nonce),
+                deliver_at,
+                expire_at: message.expire_at,
+                timestamp: message.timestamp.unwrap_or_else(now_ms),
+                dedup: false,
+                key: message.key.clone(),
+                tombstone: message.is_tombstone(),
+                message
+            })
+        };
+        for (k, data_key) in self.main_idx.range(topic_range(topic, msg.from)).flatten() {
+            if let Some(cmd) = copy(data_key, key_nonce(&k), None) {
+                cmds.push(cmd);
+            }
+        }
+        if !msg.delayed {
+            return MessageResult(cmds);
+        }
+        for (delay_key, data_key) in self.delay_idx.iter().flatten() {
+            let delay_id = key_nonce(&delay_key);
+            let deliver_at = i64::from_be_bytes(delay_key[..8].try_into().unwrap());
+            if let Some(cmd) = copy(data_key, delay_id, Some(deliver_at)).filter(|cmd| cmd.message_topic == topic) {
+                cmds.push(cmd);
+            }
+        }
+        // released by the other partition only, or the message would be delivered twice
+        self.leaving.insert(topic.to_string());
+        MessageResult(cmds)
+    }
+}
+
+impl Handler<DropTopicCmd> for StorageActor {
+    type Result = usize;
+    fn handle(&mut self, msg: DropTopicCmd, _ctx: &mut Self::Context) -> Self::Result {
+        let topic = msg.topic.as_str();
+        let nonces: Vec<u64> = self.main_idx
+            .range(topic_range(topic, 0))
+            .keys()
+            .flatten()
+            .map(|k| key_nonce(&k))
+            .collect();
+        let mut removed = nonces.iter().filter(|nonce| self.remove_message(topic, **nonce)).count();
+        for (delay_key, data_key) in self.delay_idx.iter().flatten() {
+            let delayed_topic = self.db.get(&data_key).ok().flatten()
+                .and_then(|data| Message::from_stored(&data).ok())
+                .and_then(|message| message.topic);
+            if delayed_topic.as_deref() != Some(topic) {
+                continue;
+            }
+            let rs: TransactionResult<()> = (&*self.db, &self.delay_idx).transaction(|(db, delay_idx)| {
+                delay_idx.remove(&delay_key)?;
+                db.remove(&data_key)?;
+                Ok(())
+            });
+            match rs {
+                Ok(()) => removed += 1,
+                Err(err) => eprintln!("drop delayed message of {topic} with err:{err:?}")
+            }
+        }
+        self.leaving.remove(topic);
+        println!("dropped {removed} messages of {topic}");
+        removed
+    }
+}
+
+impl Handler<RemoveCmd> for StorageActor {
+    type Result = usize;
+    fn handle(&mut self, msg: RemoveCmd, _ctx: &mut Self::Context) -> Self::Result {
+        msg.nonces
+            .iter()
+            .filter(|nonce| self.remove_message(msg.topic.as_str(), **nonce))
+            .count()
+    }
+}
 
 impl Handler<TrimCmd> for StorageActor {
-    type Result = ();
+    type Result = usize;
     fn handle(&mut self, msg: TrimCmd, _ctx: &mut Self::Context) -> Self::Result {
-        let days = msg.days;
-        let target_timestamp = today_ts() - (86400 as i64 * (days + 1) as i64) as i64;
-        println!("tar ts: {target_timestamp:?}");
-        if let Ok(Some(v)) = self.day_idx.get(i64to_vec(target_timestamp)){
-            println!("Got nonce:{v:?}");
-            for item in self.range_idx.range(..v){
-                if let Ok((rkey, data_key)) = item {
-                    let data_key2 = data_key.clone();
-                    let data_key_for_split = data_key.clone();
-                    println!("Matched key:「{data_key:?}」");
-                    if let Ok(_) = self.db.remove(data_key){
-                        println!("removed from storage");
-                    }
-                    let str_key = String::from_utf8(data_key_for_split.to_vec().try_into().unwrap()).unwrap();
-                    let topic = str_key.split('-').next().unwrap();
-                    let nonce = vectu64(rkey.to_vec());
-                    let main_key = make_key(topic, nonce);
-                    if let Ok(_) = self.main_idx.remove(main_key){
-                        println!("removed from main index");
-                    }
-                    if let Ok(_) = self.range_idx.remove(rkey){
-                        println!("removed from range index");
-                    }
-                    if let Ok(_) = self.nonce_idx.remove(data_key2){
-                        println!("removed from nonce index");
-                    }
-                }
+        println!("trim before {}", msg.before_ms);
+        let mut removed = 0;
+        for (time_key, topic) in self.time_idx.range(..i64to_vec(msg.before_ms)).flatten() {
+            let topic = String::from_utf8(topic.to_vec()).unwrap();
+            if self.remove_message(topic.as_str(), key_nonce(&time_key)) {
+                removed += 1;
+            }
+            if let Err(err) = self.time_idx.remove(time_key) {
+                eprintln!("remove time idx with err:{err}");
             }
         }
+        removed
     }
 
 }
 
 impl Handler<StorageCmd> for StorageActor {
+    type Result = Result<Receipt, String>;
+    fn handle(&mut self, msg: StorageCmd, ctx: &mut Self::Context) -> Self::Result {
+        let receipt = self.store_or_delay(msg);
+        self.notify_consumer();
+        let receipt = receipt?;
+        ctx.wait(actix::clock::sleep(Duration::from_millis(5)).into_actor(self));
+        self.db.flush().map_err(|err| format!("flush message {receipt:?} with err:{err}"))?;
+        Ok(receipt)
+    }
+}
+
+impl Handler<BatchStorageCmd> for StorageActor {
+    type Result = Result<Vec<Receipt>, String>;
+    fn handle(&mut self, msg: BatchStorageCmd, ctx: &mut Self::Context) -> Self::Result {
+        let receipts: Result<Vec<Receipt>, String> = msg.cmds.into_iter().map(|cmd| self.store_or_delay(cmd)).collect();
+        // the messages stored before a failure are delivered all the same
+        self.notify_consumer();
+        let receipts = receipts?;
+        ctx.wait(actix::clock::sleep(Duration::from_millis(5)).into_actor(self));
+        self.db.flush().map_err(|err| format!("flush batch of {} with err:{err}", receipts.len()))?;
+        Ok(receipts)
+    }
+}
+
+impl StorageActor {
+    /// Hold the message back until its deliver_at or store it right away
+    fn store_or_delay(&mut self, mut msg: StorageCmd) -> Result<Receipt, String> {
+        if msg.deliver_at.is_some_and(|at| at > now_ms()) {
+            return self.delay_message(msg);
+        }
+        // a copied delayed message that came due carries its delay id, it needs a nonce
+        if msg.deliver_at.is_some() {
+            msg.nonce = None;
+        }
+        self.store_message(msg, None)
+    }
+
     ///
-    ///   day_idx -   today_ts last -> nonce
+    ///   time_idx -  timestamp + nonce -> topic
     ///   range_idx - nonce as key -> data_key
     ///   data  -     data_key -> raw_msg
     ///   nonce -     data_key -> nonce
     ///   main_idx -  main_key -> data_key
+    ///   expire_idx - expire_at + nonce -> topic, only for messages with a ttl
+    ///   dedup_idx - data_key -> timestamp + nonce of the first publish, see `check_duplicate`
+    ///   key_idx -   topic + key -> nonce of the latest message, only on compacted topics
     ///
-    type Result = ();
-    fn handle(&mut self, msg: StorageCmd, ctx: &mut Self::Context) {
-        // let val = serde_json::to_string(message).unwrap();
-        let data_key = msg.st_key;
-        // let log_data_key = data_key.clone();
-        let data_key_in_range_idx = data_key.clone();
-        let data_key_as_nonce_idx_key = data_key.clone();
-        let data_key_as_main_idx_val = data_key.clone();
-        let nonce_as_key = Vec::from(msg.nonce.to_be_bytes());
-        let nonce_in_day_idx = nonce_as_key.clone();
-        let today_timestamp_vec = i64to_vec(today_ts());
-        // update today's last nonce index
-        let main_key = make_key(msg.message_topic.as_str(), msg.nonce);
-
-        // println!("insert {:?} with nonce {}", log_data_key, msg.nonce);
-        if let Ok(_k) = self.day_idx.insert(today_timestamp_vec, nonce_in_day_idx) {
-            //println!("update today's last nonce success!");
-            if let Ok(_) = self.range_idx.insert(nonce_as_key, data_key_in_range_idx.as_bytes()) {
-                if let Ok(_) = self.db.insert(data_key, msg.data.as_str()) {
-                    //println!("insert data success!");
-                    if let Ok(_) = self
-                        .nonce_idx
-                        .insert(data_key_as_nonce_idx_key, IVec::from(msg.nonce.to_be_bytes().to_vec()))
-                    {
-                        if let Ok(_) = self.main_idx.insert(main_key, data_key_as_main_idx_val.as_bytes()) {
-                            ctx.wait(actix::clock::sleep(Duration::from_millis(5)).into_actor(self));
-                        }else{
-                            eprintln!("insert main idx faild!");
-                        }
+    ///   every index and the data are written in one transaction, together with
+    ///   the removal of `delay_key` when a delayed message is released.
+    ///   The nonce is taken here, so a partition stores its messages in nonce order.
+    ///   Returns the stored nonce, or the original receipt when the uid was published within the dedup window
+    ///
+    fn store_message(&mut self, mut msg: StorageCmd, delay_key: Option<IVec>) -> Result<Receipt, String> {
+        let nonce = msg.nonce.unwrap_or_else(|| self.id_gen.gen_id());
+        msg.message.set_nonce(nonce);
+        let data = msg.message.to_stored();
+        let nonce_as_key = nonce.to_be_bytes();
+        let time_key = make_time_key(msg.timestamp, nonce);
+        let main_key = make_key(msg.message_topic.as_str(), nonce);
+        let expire_key = msg.expire_at.map(|expire_at| make_time_key(expire_at, nonce));
+        let data_key = msg.st_key.as_bytes();
+        let topic = msg.message_topic.as_bytes();
 
+        let window_ms = if msg.dedup { self.config.dedup_window_ms } else { 0 };
+        // on a compacted topic the message replaces the one with the same key
+        let compact_key = msg.key
+            .as_ref()
+            .filter(|_| self.config.topic(msg.message_topic.as_str()).compacted)
+            .map(|key| make_compact_key(msg.message_topic.as_str(), key.as_str()));
+
+        let rs: TransactionResult<(), Receipt> = (&*self.db, &self.time_idx, &self.range_idx, &self.nonce_idx, &self.main_idx, &self.expire_idx, &self.delay_idx, &self.dedup_idx, &self.key_idx, &self.meta)
+            .transaction(|(db, time_idx, range_idx, nonce_idx, main_idx, expire_idx, delay_idx, dedup_idx, key_idx, meta)| {
+                check_duplicate(dedup_idx, data_key, msg.timestamp, Receipt::Stored(nonce), window_ms)?;
+                raise_nonce_hwm(meta, nonce)?;
+                if let Some(compact_key) = &compact_key {
+                    if let Some(previous) = key_idx.get(compact_key)? {
+                        let trees = MessageTrees { db, time_idx, range_idx, nonce_idx, main_idx, key_idx };
+                        trees.remove(msg.message_topic.as_str(), vectu64(previous.to_vec()))?;
+                    }
+                    // a tombstone only clears the key, it stays for live consumers until it expires
+                    if msg.tombstone {
+                        key_idx.remove(compact_key.as_slice())?;
                     } else {
-                        eprintln!("insert nonce idx faild!");
+                        key_idx.insert(compact_key.as_slice(), &nonce_as_key)?;
                     }
-                } else {
-                    eprintln!("insert data faild!");
                 }
-            } else {
-                eprintln!("update range index faild!");
+                time_idx.insert(time_key.as_slice(), topic)?;
+                range_idx.insert(&nonce_as_key, data_key)?;
+                db.insert(data_key, data.as_slice())?;
+                nonce_idx.insert(data_key, &nonce_as_key)?;
+                main_idx.insert(&main_key, data_key)?;
+                if let Some(expire_key) = &expire_key {
+                    expire_idx.insert(expire_key.as_slice(), topic)?;
+                }
+                if let Some(delay_key) = &delay_key {
+                    delay_idx.remove(delay_key)?;
+                    release_duplicate(dedup_idx, data_key, key_nonce(delay_key), nonce)?;
+                }
+                Ok(())
+            });
+        match rs {
+            Ok(()) => {},
+            Err(TransactionError::Abort(original)) => {
+                println!("duplicate publish of {} stored as {original:?}", msg.st_key);
+                return Ok(original);
             }
-        } else {
-            eprintln!("update today's last nonce faild!");
-        };
-        
+            Err(err) => {
+                let err = format!("store message {nonce} with err:{err:?}");
+                eprintln!("{err}");
+                return Err(err);
+            }
+        }
+        let latest = self.notify.entry(msg.message_topic).or_default();
+        *latest = (*latest).max(nonce);
+        Ok(Receipt::Stored(nonce))
     }
-}
\ No newline at end of file
+
+    /// Wake the consumer up once per topic stored to; `do_send` so a full mailbox cannot lose it
+    fn notify_consumer(&mut self) {
+        for (topic, nonce) in self.notify.drain() {
+            self.consumer_addr.do_send(NewMessageCmd{topic, nonce});
+        }
+    }
+
+    ///
+    ///   delay_idx - deliver_at + delay id -> data_key
+    ///
+    ///   the data is kept in `db` but stays out of the other indexes until it is due,
+    ///   the delay id comes from the nonce generator but the message gets its nonce on release
+    ///
+    fn delay_message(&mut self, msg: StorageCmd) -> Result<Receipt, String> {
+        let delay_id = msg.nonce.unwrap_or_else(|| self.id_gen.gen_id());
+        let data = msg.message.to_stored();
+        let data_key = msg.st_key;
+        let delay_key = make_time_key(msg.deliver_at.unwrap_or_default(), delay_id);
+        let window_ms = if msg.dedup { self.config.dedup_window_ms } else { 0 };
+        let rs: TransactionResult<(), Receipt> = (&*self.db, &self.delay_idx, &self.dedup_idx, &self.meta).transaction(|(db, delay_idx, dedup_idx, meta)| {
+            check_duplicate(dedup_idx, data_key.as_bytes(), msg.timestamp, Receipt::Delayed { delay_id }, window_ms)?;
+            raise_nonce_hwm(meta, delay_id)?;
+            db.insert(data_key.as_bytes(), data.as_slice())?;
+            delay_idx.insert(delay_key.as_slice(), data_key.as_bytes())?;
+            Ok(())
+        });
+        match rs {
+            Ok(()) => Ok(Receipt::Delayed { delay_id }),
+            Err(TransactionError::Abort(original)) => {
+                println!("duplicate publish of {data_key} stored as {original:?}");
+                Ok(original)
+            }
+            Err(err) => {
+                let err = format!("store delayed message {delay_id} with err:{err:?}");
+                eprintln!("{err}");
+                Err(err)
+            }
+        }
+    }
+
+    /// Move the delayed messages that are due into the indexes consumers read
+    fn release_due(&mut self) {
+        let due_key = i64to_vec(now_ms() + 1);
+        for (delay_key, data_key) in self.delay_idx.range(..due_key).flatten() {
+            let Ok(Some(data)) = self.db.get(&data_key) else {
+                let _ = self.delay_idx.remove(delay_key);
+                continue;
+            };
+            let Ok(mut message) = Message::from_stored(&data) else {
+                eprintln!("invalid delayed message:{data_key:?}");
+                let _ = self.delay_idx.remove(delay_key);
+                continue;
+            };
+            let Some(topic) = message.got_topic() else {
+                let _ = self.delay_idx.remove(delay_key);
+                continue;
+            };
+            if self.leaving.contains(&topic) {
+                continue;
+            }
+            if message.is_expired() {
+                let _ = self.db.remove(&data_key);
+                let _ = self.delay_idx.remove(delay_key);
+                continue;
+            }
+            // a fresh nonce puts the message after everything consumers already read
+            let timestamp = now_ms();
+            message.timestamp = Some(timestamp);
+            let cmd = StorageCmd {
+                st_key: String::from_utf8(data_key.to_vec()).unwrap(),
+                message_topic: topic,
+                nonce: None,
+                deliver_at: None,
+                expire_at: message.expire_at,
+                timestamp,
+                dedup: false,
+                key: message.key.clone(),
+                tombstone: message.is_tombstone(),
+                message
+            };
+            let _ = self.store_message(cmd, Some(delay_key));
+        }
+        self.notify_consumer();
+    }
+
+    /// Drop every message whose ttl ran out from all of its indexes
+    fn sweep_expired(&mut self) {
+        let due_key = i64to_vec(now_ms() + 1);
+        let mut count = 0;
+        for (expire_key, topic) in self.expire_idx.range(..due_key).flatten() {
+            let nonce = key_nonce(&expire_key);
+            let topic = String::from_utf8(topic.to_vec()).unwrap();
+            if self.remove_message(topic.as_str(), nonce) {
+                count += 1;
+            }
+            if let Err(err) = self.expire_idx.remove(expire_key) {
+                eprintln!("remove expire idx with err:{err}");
+            }
+        }
+        if count > 0 {
+            println!("swept {count} expired messages");
+        }
+    }
+
+    /// Forget the uids published before the dedup window
+    fn prune_dedup(&mut self) {
+        let before_ms = now_ms() - self.config.dedup_window_ms as i64;
+        for (data_key, published) in self.dedup_idx.iter().flatten() {
+            if i64::from_be_bytes(published[..8].try_into().unwrap()) < before_ms {
+                if let Err(err) = self.dedup_idx.remove(data_key) {
+                    eprintln!("remove dedup idx with err:{err}");
+                }
+            }
+        }
+    }
+
+    /// Drop the oldest messages of every topic above its max_messages or max_bytes
+    fn enforce_retention(&mut self) {
+        let policies: Vec<(String, u64, u64)> = self.config.topics
+            .iter()
+            .filter(|(_, tc)| tc.has_retention())
+            .map(|(topic, tc)| (topic.clone(), tc.max_messages.unwrap_or(u64::MAX), tc.max_bytes.unwrap_or(u64::MAX)))
+            .collect();
+        for (topic, max_messages, max_bytes) in policies {
+            let mut messages = 0;
+            let mut bytes = 0;
+            let mut outdated: Vec<u64> = vec![];
+            // newest first, everything past the limits is outdated
+            for (k, data_key) in self.main_idx.range(topic_range(topic.as_str(), 0)).rev().flatten() {
+                if messages >= max_messages || bytes >= max_bytes {
+                    outdated.push(key_nonce(&k));
+                    continue;
+                }
+                let size = self.db.get(data_key).ok().flatten().map(|v| v.len() as u64).unwrap_or_default();
+                if bytes + size > max_bytes {
+                    outdated.push(key_nonce(&k));
+                    bytes = max_bytes;
+                    continue;
+                }
+                messages += 1;
+                bytes += size;
+            }
+            if outdated.is_empty() {
+                continue;
+            }
+            let count = outdated.iter().filter(|nonce| self.remove_message(topic.as_str(), **nonce)).count();
+            println!("retention removed {count} messages of {topic}");
+        }
+    }
+}
+
+/// Abort with the original receipt when `data_key` was published less than `window_ms` before `timestamp`,
+/// record this publish otherwise as timestamp + nonce, followed by a marker byte for a delay id
+fn check_duplicate(dedup_idx: &TransactionalTree, data_key: &[u8], timestamp: i64, receipt: Receipt, window_ms: u64) -> ConflictableTransactionResult<(), Receipt> {
+    if window_ms == 0 {
+        return Ok(());
+    }
+    if let Some(published) = dedup_idx.get(data_key)? {
+        if i64::from_be_bytes(published[..8].try_into().unwrap()) + window_ms as i64 > timestamp {
+            let id = key_nonce(&published[..16]);
+            let original = if published.len() > 16 { Receipt::Delayed { delay_id: id } } else { Receipt::Stored(id) };
+            return Err(ConflictableTransactionError::Abort(original));
+        }
+    }
+    let mut record = make_time_key(timestamp, receipt.nonce().or(receipt.delay_id()).unwrap_or_default());
+    if receipt.delay_id().is_some() {
+        record.push(DELAYED_MARKER);
+    }
+    dedup_idx.insert(data_key, record)?;
+    Ok(())
+}
+
+/// Point the dedup record of a released delayed message at its nonce, so a retry gets the nonce
+fn release_duplicate(dedup_idx: &TransactionalTree, data_key: &[u8], delay_id: u64, nonce: u64) -> Result<(), UnabortableTransactionError> {
+    if let Some(published) = dedup_idx.get(data_key)? {
+        if published.len() > 16 && key_nonce(&published[..16]) == delay_id {
+            let published_at = i64::from_be_bytes(published[..8].try_into().unwrap());
+            dedup_idx.insert(data_key, make_time_key(published_at, nonce))?;
+        }
+    }
+    Ok(())
+}
+
+/// Distinct topics of main_idx
+pub fn stored_topics(main_idx: &sled::Tree) -> Vec<String> {
+    let mut topics: Vec<String> = vec![];
+    let mut next: Vec<u8> = vec![];
+    // jump from one topic to the next, main keys are the topic, its terminator and the nonce
+    while let Some(Ok((key, _))) = main_idx.range(next.as_slice()..).next() {
+        let topic = key_topic(&key);
+        next = make_key(topic.as_str(), u64::MAX).to_vec();
+        next.push(0);
+        topics.push(topic);
+    }
+    topics
+}
+
+/// Record `nonce` as the high-water mark unless a higher one is already there
+fn raise_nonce_hwm(meta: &TransactionalTree, nonce: u64) -> Result<(), UnabortableTransactionError> {
+    let hwm = meta.get(NONCE_HWM_KEY)?.map(|v| vectu64(v.to_vec())).unwrap_or_default();
+    if nonce > hwm {
+        meta.insert(NONCE_HWM_KEY, &nonce.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+/// Highest nonce ever stored in the partition of `meta`, 0 for data written before it was recorded
+pub fn nonce_hwm(meta: &sled::Tree) -> u64 {
+    match meta.get(NONCE_HWM_KEY) {
+        Ok(Some(v)) => vectu64(v.to_vec()),
+        _ => 0
+    }
+}
+
+/// Rewrite the keys of the legacy main_idx, the topic directly followed by the nonce,
+/// into `main_idx` with the terminated topic of `make_key`, then drop the legacy tree.
+/// A legacy key of `orders` can not be told apart from one of `orders.eu` by range,
+/// so each topic is read from the message itself.
+pub fn migrate_main_idx(db: &sled::Db, main_idx: &sled::Tree) {
+    let Ok(legacy_idx) = db.open_tree(LEGACY_MAIN_IDX) else {
+        return;
+    };
+    if legacy_idx.is_empty() {
+        return;
+    }
+    let mut migrated = 0;
+    for (key, data_key) in legacy_idx.iter().flatten() {
+        let topic = db.get(&data_key).ok().flatten()
+            .and_then(|data| Message::from_stored(&data).ok())
+            .and_then(|message| message.topic)
+            .unwrap_or_else(|| String::from_utf8_lossy(&key[..key.len() - 8]).to_string());
+        if main_idx.insert(make_key(topic.as_str(), key_nonce(&key)), data_key).is_ok() {
+            migrated += 1;
+        }
+    }
+    println!("migrated {migrated} keys from {LEGACY_MAIN_IDX} to terminated topic keys");
+    if let Err(err) = db.drop_tree(LEGACY_MAIN_IDX) {
+        eprintln!("drop {LEGACY_MAIN_IDX} with err:{err}");
+    }
+}
+
+/// Fill time_idx from the legacy day_idx (local day start -> last nonce of the day)
+/// for data written before the time index existed, then drop day_idx.
+/// Each message gets the end of its day, so retention never removes it too early.
+pub fn migrate_day_idx(db: &sled::Db, range_idx: &sled::Tree, time_idx: &sled::Tree) {
+    let Ok(day_idx) = db.open_tree("day_idx") else {
+        return;
+    };
+    if day_idx.is_empty() {
+        return;
+    }
+    let days: Vec<(i64, u64)> = day_idx
+        .iter()
+        .flatten()
+        .map(|(day, nonce)| (i64::from_be_bytes(day.to_vec().try_into().unwrap()), vectu64(nonce.to_vec())))
+        .collect();
+    let mut migrated = 0;
+    for (rkey, data_key) in range_idx.iter().flatten() {
+        let nonce = vectu64(rkey.to_vec());
+        let timestamp = days
+            .iter()
+            .find(|(_, last_nonce)| *last_nonce >= nonce)
+            .map(|(day, _)| (day + 86400) * 1000 - 1)
+            .unwrap_or_else(now_ms);
+        let topic = db.get(&data_key).ok().flatten()
+            .and_then(|data| Message::from_stored(&data).ok())
+            .and_then(|message| message.topic);
+        if let Some(topic) = topic {
+            if time_idx.insert(make_time_key(timestamp, nonce), topic.as_bytes()).is_ok() {
+                migrated += 1;
+            }
+        }
+    }
+    println!("migrated {migrated} messages from day_idx to time_idx");
+    if let Err(err) = db.drop_tree("day_idx") {
+        eprintln!("drop day_idx with err:{err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::websocks::topic_matches;
+
+    fn temporary_db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn prefixed_topics_stay_apart() {
+        let db = temporary_db();
+        let main_idx = db.open_tree(MAIN_IDX).unwrap();
+        main_idx.insert(make_key("orders", 1), "orders-a").unwrap();
+        main_idx.insert(make_key("orders.eu", 2), "orders.eu-b").unwrap();
+        main_idx.insert(make_key("orders", 3), "orders-c").unwrap();
+        assert_eq!(stored_topics(&main_idx), vec!["orders", "orders.eu"]);
+        let nonces: Vec<u64> = main_idx.range(topic_range("orders", 0)).keys().flatten().map(|k| key_nonce(&k)).collect();
+        assert_eq!(nonces, vec![1, 3]);
+        let nonces: Vec<u64> = main_idx.range(topic_range("orders.eu", 0)).keys().flatten().map(|k| key_nonce(&k)).collect();
+        assert_eq!(nonces, vec![2]);
+    }
+
+    #[test]
+    fn pattern_finds_topics_behind_their_prefix() {
+        let db = temporary_db();
+        let main_idx = db.open_tree(MAIN_IDX).unwrap();
+        for (topic, nonce) in [("orders", 1), ("orders.eu", 2), ("orders.eu.created", 3), ("ordersx", 4)] {
+            main_idx.insert(make_key(topic, nonce), topic).unwrap();
+        }
+        let matched: Vec<String> = stored_topics(&main_idx).into_iter().filter(|t| topic_matches("orders.#", t)).collect();
+        assert_eq!(matched, vec!["orders", "orders.eu", "orders.eu.created"]);
+        // a subscriber of `orders` reads only its own messages past its cursor
+        let nonces: Vec<u64> = main_idx.range(topic_range("orders", 1)).keys().flatten().map(|k| key_nonce(&k)).collect();
+        assert_eq!(nonces, vec![1]);
+    }
+
+    #[test]
+    fn legacy_keys_are_migrated() {
+        let db = temporary_db();
+        let legacy_idx = db.open_tree(LEGACY_MAIN_IDX).unwrap();
+        for (topic, nonce) in [("orders", 1u64), ("orders.eu", 2), ("orders", 3)] {
+            let data_key = format!("{topic}-u{nonce}");
+            let message: Message = serde_json::from_str(&format!(r#"{{"uid":"u{nonce}","topic":"{topic}"}}"#)).unwrap();
+            db.insert(data_key.as_bytes(), message.to_stored()).unwrap();
+            let mut key = Vec::from(topic.as_bytes());
+            key.extend_from_slice(&nonce.to_be_bytes());
+            legacy_idx.insert(key, data_key.as_bytes()).unwrap();
+        }
+        let main_idx = db.open_tree(MAIN_IDX).unwrap();
+        migrate_main_idx(&db, &main_idx);
+        assert_eq!(stored_topics(&main_idx), vec!["orders", "orders.eu"]);
+        assert_eq!(main_idx.get(make_key("orders.eu", 2)).unwrap().unwrap(), "orders.eu-u2".as_bytes());
+        assert!(!db.tree_names().iter().any(|name| name == LEGACY_MAIN_IDX.as_bytes()));
+    }
+}
@@ -2,17 +2,19 @@ extern crate rand;
 use actix::prelude::*;
 use actix::{Actor, StreamHandler};
 use actix_web_actors::ws;
-use chrono::prelude::*;
-use chrono::NaiveDate;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use serde::{Deserialize, Serialize};
+use serde_json::to_string_pretty;
 use sled::IVec;
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 // use std::time::{SystemTime, UNIX_EPOCH};
 // use super::conn_mng::{AppendCmd, RemoveCmd, MsgCmd, ClearCmd, ConnectionActor};
 use super::partition::PartitionDispacher;
-
-#[derive(Debug, PartialEq)]
-struct TransactionError;
+use super::consumer::SubscribeOpts;
 
 //fn got_timestamp() -> u128 {
 //    let now = SystemTime::now();
@@ -27,16 +29,9 @@ struct TransactionError;
 //    got_timestamp() - last
 //}
 
-pub fn today_ts() -> i64 {
-    let today = NaiveDate::from_ymd_opt(
-        chrono::Local::now().year(),
-        chrono::Local::now().month(),
-        chrono::Local::now().day(),
-    )
-    .unwrap();
-    let dt = chrono::NaiveDateTime::new(today, chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap());
-    let timestamp = dt.timestamp();
-    timestamp
+/// current unix time in milliseconds
+pub fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
 }
 
 pub fn i64to_vec(n: i64) -> Vec<u8> {
@@ -47,43 +42,121 @@ pub fn vectu64(vc: Vec<u8>) -> u64 {
     u64::from_be_bytes(vc.try_into().unwrap())
 }
 
+/// main_idx key: the topic, a `\0` terminator so the keys of `orders` never cover `orders.eu`, then the nonce
 pub fn make_key(topic: &str, nonce: u64) -> IVec {
-    let mut nonce_vec = Vec::from(nonce.to_be_bytes());
-    let mut topic_vec = Vec::from(topic.as_bytes());
-    topic_vec.append(&mut nonce_vec);
-    IVec::from(topic_vec)
+    let mut key_vec = Vec::from(topic.as_bytes());
+    key_vec.push(0);
+    key_vec.extend_from_slice(&nonce.to_be_bytes());
+    IVec::from(key_vec)
+}
+
+/// main_idx keys of `topic` from nonce `from` on
+pub fn topic_range(topic: &str, from: u64) -> RangeInclusive<IVec> {
+    make_key(topic, from)..=make_key(topic, u64::MAX)
+}
+
+/// topic part of a key built by `make_key`
+pub fn key_topic(key: &[u8]) -> String {
+    String::from_utf8_lossy(&key[..key.len() - 9]).to_string()
+}
+
+/// key ordered by time first, `ts` in epoch ms followed by the nonce
+pub fn make_time_key(ts: i64, nonce: u64) -> Vec<u8> {
+    let mut key_vec = i64to_vec(ts);
+    key_vec.extend_from_slice(&nonce.to_be_bytes());
+    key_vec
+}
+
+/// key of the latest message for `key` on a compacted `topic`
+pub fn make_compact_key(topic: &str, key: &str) -> Vec<u8> {
+    let mut key_vec = Vec::from(topic.as_bytes());
+    key_vec.push(0);
+    key_vec.extend_from_slice(key.as_bytes());
+    key_vec
+}
+
+/// nonce part of a key built by `make_key`
+pub fn key_nonce(key: &[u8]) -> u64 {
+    vectu64(key[key.len() - 8..].to_vec())
+}
+
+/// key of the committed offset of `client_id` on `topic`
+pub fn make_offset_key(client_id: &str, topic: &str) -> IVec {
+    let mut key_vec = Vec::from(client_id.as_bytes());
+    key_vec.push(0);
+    key_vec.extend_from_slice(topic.as_bytes());
+    IVec::from(key_vec)
+}
+
+/// topic receiving the messages of `topic` that failed too many deliveries
+pub fn dlq_topic(topic: &str) -> String {
+    format!("{topic}.dlq")
+}
+
+/// A subscription pattern uses `*` for one `.` separated level of a topic and `#` for any number of them
+pub fn is_pattern(topic: &str) -> bool {
+    topic.split('.').any(|level| level == "*" || level == "#")
 }
 
+/// Whether `topic` is one of the topics `pattern` stands for,
+/// dead letter topics only match a pattern ending in `.dlq`
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    fn matches(pattern: &[&str], topic: &[&str]) -> bool {
+        match pattern.split_first() {
+            None => topic.is_empty(),
+            Some((&"#", rest)) => (0..=topic.len()).any(|skip| matches(rest, &topic[skip..])),
+            Some((&"*", rest)) => !topic.is_empty() && matches(rest, &topic[1..]),
+            Some((level, rest)) => topic.first() == Some(level) && matches(rest, &topic[1..]),
+        }
+    }
+    if topic.ends_with(".dlq") && !pattern.ends_with(".dlq") {
+        return false;
+    }
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let topic: Vec<&str> = topic.split('.').collect();
+    matches(&pattern, &topic)
+}
+
+/// subscription id shared by the members of a consumer group
+pub fn group_sub_id(group: &str) -> String {
+    format!("group:{group}")
+}
+
+/// group name of a subscription built by `group_sub_id`
+pub fn sub_group(sub_id: &str) -> Option<&str> {
+    sub_id.strip_prefix("group:")
+}
+
+///
+///  Nonce source shared by every partition.
+///  Nonces are unique across partitions, so a nonce identifies a message without its topic.
+///  The storage actor of a partition takes the nonce when it stores a message, so within
+///  a partition nonces increase in storage order, the order consumers read them in.
+///  There is no order between messages of different partitions.
+///
 #[derive(Debug, Clone)]
 pub struct IdGenerator {
-    max_id: Arc<Mutex<u64>>,
+    max_id: Arc<AtomicU64>,
 }
 
 impl IdGenerator {
     pub fn new(init_id: u64) -> IdGenerator {
         IdGenerator {
-            max_id: Arc::new(Mutex::new(init_id)),
+            max_id: Arc::new(AtomicU64::new(init_id)),
         }
     }
 
+    /// Make sure the next nonce is above `init_id`, never moves backwards
     pub fn init_with(&self, init_id: u64) {
-        let mut max_id = self.max_id.lock().unwrap();
-        *max_id = init_id;
+        self.max_id.fetch_max(init_id, Ordering::SeqCst);
     }
 
     pub fn gen_id(&self) -> u64 {
-        let mut max_id = self.max_id.lock().unwrap();
-        *max_id += 1;
-        let new_id = *max_id;
-        new_id
+        self.max_id.fetch_add(1, Ordering::SeqCst) + 1
     }
 
     pub fn get_max_id(&self) -> u64 {
-        if let Ok(mid) = self.max_id.lock() {
-            *mid
-        }else{
-            0
-        }
+        self.max_id.load(Ordering::SeqCst)
     }
 
 }
@@ -98,37 +171,143 @@ pub struct Message {
     pub params: Option<Vec<String>>,
     pub offset: Option<u64>,
     pub nonce: Option<u64>,
+    pub group: Option<String>,
+    pub credit: Option<u64>,
+    /// set on dead letters: where the message came from and how often it failed
+    pub origin_topic: Option<String>,
+    pub origin_nonce: Option<u64>,
+    pub failures: Option<u32>,
+    /// epoch ms the message becomes visible, or a delay from the publish time
+    pub deliver_at: Option<i64>,
+    pub delay_ms: Option<u64>,
+    /// lifetime after the message becomes visible, `expire_at` is set by the server from it
+    pub ttl_ms: Option<u64>,
+    pub expire_at: Option<i64>,
+    /// server time of the publish in epoch ms, utc
+    pub timestamp: Option<i64>,
+    /// subscribe: replay from the first message published at or after this epoch ms
+    pub since: Option<i64>,
+    /// free form attributes such as trace id, content type or tenant, kept with the message
+    pub headers: Option<HashMap<String, String>>,
+    /// subscribe: only deliver messages carrying all of these headers
+    pub filter: Option<HashMap<String, String>>,
+    /// subscribe: deliver every message as a binary frame
+    pub binary: Option<bool>,
+    /// subscribe: keep every delivery in flight until it is acked, redelivered otherwise
+    pub ack: Option<bool>,
+    /// `base64` when the raw payload of a binary message is delivered in a text frame
+    pub encoding: Option<String>,
+    /// raw payload of a binary message, kept out of the json
+    #[serde(skip)]
+    pub body: Option<Vec<u8>>,
 }
 
+/// first byte of a stored binary message, stored json never starts with it
+const BINARY_MARKER: u8 = 0;
+
 impl Message {
     pub fn got_topic(&mut self) -> Option<String> {
-        if let Some(topic) = &self.topic {
-            Some(topic.clone())
-        } else {
-            None
-        }
+        self.topic.clone()
     }
     pub fn got_cmd(&mut self) -> Option<String> {
-        if let Some(cmd) = &self.cmd {
-            Some(cmd.clone())
-        } else {
-            None
-        }
+        self.cmd.clone()
     }
     pub fn got_offset(&mut self) -> Option<u64> {
-        if let Some(offset) = &self.offset {
-            Some(*offset)
-        } else {
-            None
-        }
+        self.offset
     }
     pub fn got_params(&mut self) -> Option<Vec<String>> {
-        if let Some(params) = &self.params {
-            Some(params.clone())
-        } else {
-            None
+        self.params.clone()
+    }
+    pub fn got_group(&mut self) -> Option<String> {
+        self.group.clone()
+    }
+    pub fn got_credit(&mut self) -> Option<u64> {
+        self.credit
+    }
+    pub fn got_since(&mut self) -> Option<i64> {
+        self.since
+    }
+    /// nonces referenced by an ack/nack: the `nonce` field plus every numeric param
+    pub fn got_nonces(&mut self) -> Result<Vec<u64>, String> {
+        let mut nonces: Vec<u64> = self.nonce.into_iter().collect();
+        for param in self.got_params().unwrap_or_default() {
+            match param.parse::<u64>() {
+                Ok(nonce) => nonces.push(nonce),
+                Err(err) => return Err(format!("Invalid nonce {param}:{err}"))
+            }
+        }
+        Ok(nonces)
+    }
+    /// epoch ms the message becomes visible, `None` for an immediate message
+    pub fn got_deliver_at(&mut self) -> Option<i64> {
+        match (self.deliver_at, self.delay_ms) {
+            (Some(at), _) => Some(at),
+            (None, Some(delay)) => Some(now_ms() + delay as i64),
+            (None, None) => None
+        }
+    }
+    /// keyed message without payload, deletes the key on a compacted topic
+    pub fn is_tombstone(&self) -> bool {
+        self.key.is_some() && self.payload.is_none() && self.body.is_none()
+    }
+    pub fn is_expired(&self) -> bool {
+        self.expire_at.is_some_and(|at| at <= now_ms())
+    }
+    /// Bytes kept in `db`: the json, or the marker and the binary frame when there is a raw payload
+    pub fn to_stored(&self) -> Vec<u8> {
+        match self.body {
+            Some(_) => {
+                let mut data = vec![BINARY_MARKER];
+                data.extend(self.to_frame());
+                data
+            }
+            None => to_string_pretty(self).unwrap().into_bytes()
         }
     }
+    pub fn from_stored(data: &[u8]) -> Result<Message, String> {
+        match data.first() {
+            Some(&BINARY_MARKER) => Message::from_frame(&data[1..]),
+            _ => serde_json::from_slice(data).map_err(|err| format!("Invalid json:{err}"))
+        }
+    }
+    /// Binary frame: header length as u32 big endian, the json header, then the raw payload
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut header = self.clone();
+        let body = header.body.take()
+            .or_else(|| header.payload.take().map(String::into_bytes))
+            .unwrap_or_default();
+        let header = serde_json::to_vec(&header).unwrap();
+        let mut frame = Vec::with_capacity(4 + header.len() + body.len());
+        frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        frame.extend(header);
+        frame.extend(body);
+        frame
+    }
+    pub fn from_frame(frame: &[u8]) -> Result<Message, String> {
+        let Some(len) = frame.get(..4) else {
+            return Err("Frame too short".to_string());
+        };
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        let Some(header) = frame.get(4..4 + len) else {
+            return Err(format!("Header length {len} exceeds frame"));
+        };
+        let mut message: Message = serde_json::from_slice(header).map_err(|err| format!("Invalid header:{err}"))?;
+        message.payload = None;
+        message.encoding = None;
+        message.body = Some(frame[4 + len..].to_vec());
+        Ok(message)
+    }
+    /// Json for a text frame, the raw payload of a binary message is base64 encoded
+    pub fn to_text(&self) -> String {
+        let Some(body) = &self.body else {
+            return to_string_pretty(self).unwrap();
+        };
+        let mut message = self.clone();
+        message.payload = Some(STANDARD.encode(body));
+        message.encoding = Some("base64".to_string());
+        message.body = None;
+        to_string_pretty(&message).unwrap()
+    }
     pub fn set_nonce(&mut self, nonce: u64) {
         self.nonce = Some(nonce);
     }
@@ -136,24 +315,122 @@ impl Message {
 
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct InnerMessage(pub String);
+pub struct InnerMessage(pub Vec<u8>);
 
 
 #[derive(Serialize, Deserialize)]
-pub struct Command {
-    cmd: String,
-    params: Vec<String>,
+pub struct ErrResp {
+    pub rs: bool,
+    pub detail: String,
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct ErrResp {
+pub struct TrimResp {
+    pub rs: bool,
+    /// partition index -> removed messages
+    pub removed: HashMap<u16, usize>,
+}
+
+/// outcome of storing one message: its nonce, or the delay id of a message held back
+/// until its deliver_at, which only gets a nonce once it is due
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Receipt {
+    Stored(u64),
+    Delayed { delay_id: u64 },
+}
+
+impl Receipt {
+    pub fn nonce(&self) -> Option<u64> {
+        match self {
+            Receipt::Stored(nonce) => Some(*nonce),
+            Receipt::Delayed { .. } => None,
+        }
+    }
+
+    pub fn delay_id(&self) -> Option<u64> {
+        match self {
+            Receipt::Stored(_) => None,
+            Receipt::Delayed { delay_id } => Some(*delay_id),
+        }
+    }
+}
+
+/// where a published message was stored, a delayed message has a delay_id instead of a nonce
+#[derive(Serialize, Deserialize)]
+pub struct PublishResp {
     pub rs: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay_id: Option<u64>,
+    pub partition: u16,
+    pub key: String,
+}
+
+/// sent back on the websocket once a published message is stored
+#[derive(Serialize, Deserialize)]
+pub struct ConfirmResp {
+    pub rs: bool,
+    pub uid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay_id: Option<u64>,
+}
+
+/// sent back on the websocket when a published message could not be stored
+#[derive(Serialize, Deserialize)]
+pub struct ConfirmErrResp {
+    pub rs: bool,
+    pub uid: String,
     pub detail: String,
 }
 
+/// nonces of a published batch in the order of its messages, `{"delay_id":..}` for delayed ones
+#[derive(Serialize, Deserialize)]
+pub struct BatchResp {
+    pub rs: bool,
+    pub nonces: Vec<Receipt>,
+}
+
+/// sent back on the websocket once a batch is stored
+#[derive(Serialize, Deserialize)]
+pub struct BatchConfirmResp {
+    pub rs: bool,
+    pub uids: Vec<String>,
+    pub nonces: Vec<Receipt>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RedriveResp {
+    pub rs: bool,
+    pub topic: String,
+    pub redriven: usize,
+}
+
+/// partition count and the progress of the latest migration, false while topics are left behind
+#[derive(Serialize, Deserialize)]
+pub struct PartitionsResp {
+    pub rs: bool,
+    pub partitions: u16,
+    pub rebalancing: bool,
+    /// topics still served by the partition they are leaving
+    pub moving: Vec<String>,
+    /// topics migrated to another partition
+    pub moved: Vec<String>,
+    /// why topics were left behind
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
 pub struct WsSession {
     pub client_id: String,
-    pub dispacher: PartitionDispacher
+    pub dispacher: PartitionDispacher,
+    /// deliver messages as binary frames, chosen on subscribe
+    pub binary: bool,
+    /// a subscription asked for acks, credit only limits those
+    pub ack: bool
 }
 
 impl Actor for WsSession {
@@ -173,7 +450,17 @@ impl Handler<InnerMessage> for WsSession {
     type Result = ();
 
     fn handle(&mut self, msg: InnerMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        let stored = msg.0;
+        if !self.binary && stored.first() != Some(&BINARY_MARKER) {
+            // stored json goes out as it is
+            ctx.text(String::from_utf8(stored).unwrap_or_default());
+            return;
+        }
+        match Message::from_stored(&stored) {
+            Ok(message) if self.binary => ctx.binary(message.to_frame()),
+            Ok(message) => ctx.text(message.to_text()),
+            Err(err) => eprintln!("invalid stored message for {}:{err}", self.client_id)
+        }
     }
 }
 
@@ -182,6 +469,22 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         match msg {
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            // a json array is a batch of messages to publish
+            Ok(ws::Message::Text(text)) if text.trim_start().starts_with('[') => {
+                match serde_json::from_str::<Vec<Message>>(&text) {
+                    Ok(messages) => self.dispatch_batch(messages, ctx),
+                    Err(err) => {
+                        println!("Invalid Batch:{} error:{}", text, err);
+                        ctx.text(
+                            serde_json::to_string(&ErrResp {
+                                rs: false,
+                                detail: format!("Invalid json:{err}"),
+                            })
+                            .unwrap(),
+                        )
+                    }
+                };
+            }
             Ok(ws::Message::Text(text)) => {
                 match serde_json::from_str::<Message>(&text) {
                     Ok(msg) => {
@@ -201,7 +504,11 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
                     }
                 };
             }
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Ok(ws::Message::Binary(bin)) => match Message::from_frame(&bin) {
+                Ok(mut msg) if msg.topic.is_some() => self.dispatch_message(&mut msg, ctx),
+                Ok(_) => ctx.text("{\"rs\":false,\"detail\":\"Missing topic\"}"),
+                Err(detail) => ctx.text(serde_json::to_string(&ErrResp { rs: false, detail }).unwrap()),
+            },
             Ok(ws::Message::Close(_)) => {
                 ctx.close(Some(ws::CloseReason {
                     code: ws::CloseCode::Normal,
@@ -219,30 +526,153 @@ impl WsSession {
             // this is a command
             let command = cmd.clone();
             let command_str = command.as_str();
-            let params = if let Some(p) = message.got_params() {
-                p
-            } else {
-                vec![]
-            };
-            let offset = if let Some(off) = message.got_offset() {
-                off
-            } else {
-                0 as u64
-            };
+            let params = message.got_params().unwrap_or_default();
+            // no offset means resume from the committed one
+            let offset = message.got_offset();
             //self.process_command(command_str, params, offset);
-            if command_str == "subscribe" {
-                let topics = params;
-                self.dispacher.subscribe(ctx.address(), self.client_id.as_str(), topics, offset);
-                ctx.text("{\"rs\":true,\"detail\":\"Subscribe Success\"}");
+            match command_str {
+                "subscribe" => {
+                    let topics = params;
+                    // an explicit offset wins over `since`
+                    let opts = SubscribeOpts {
+                        group: message.got_group(),
+                        offset,
+                        since: message.got_since(),
+                        filter: message.filter.clone(),
+                        ack: message.ack.unwrap_or(false)
+                    };
+                    if let Some(binary) = message.binary {
+                        self.binary = binary;
+                    }
+                    self.ack |= opts.ack;
+                    self.dispacher.subscribe(ctx.address(), self.client_id.as_str(), topics, opts);
+                    ctx.text("{\"rs\":true,\"detail\":\"Subscribe Success\"}");
+                }
+                "unsubscribe" => {
+                    if params.is_empty() {
+                        ctx.text("{\"rs\":false,\"detail\":\"Missing topics\"}");
+                    } else {
+                        self.dispacher.unsubscribe_topics(self.client_id.as_str(), params);
+                        ctx.text("{\"rs\":true,\"detail\":\"Unsubscribe Success\"}");
+                    }
+                }
+                "credit" => match message.got_credit() {
+                    // messages without ack are never outstanding, a credit would not limit them
+                    Some(_) if !self.ack => {
+                        ctx.text("{\"rs\":false,\"detail\":\"Credit only applies to subscriptions with ack\"}");
+                    }
+                    // no topic means every subscribed topic without a credit of its own
+                    Some(credit) => match self.dispacher.credit(self.client_id.as_str(), params, credit) {
+                        Ok(()) => ctx.text("{\"rs\":true,\"detail\":\"Credit Success\"}"),
+                        Err(detail) => ctx.text(serde_json::to_string(&ErrResp { rs: false, detail }).unwrap()),
+                    },
+                    None => {
+                        ctx.text("{\"rs\":false,\"detail\":\"Missing credit\"}");
+                    }
+                },
+                "ack" | "nack" => match message.got_nonces() {
+                    Ok(nonces) if command_str == "ack" => self.dispacher.ack(self.client_id.as_str(), nonces),
+                    Ok(nonces) => self.dispacher.nack(self.client_id.as_str(), nonces),
+                    Err(detail) => {
+                        ctx.text(serde_json::to_string(&ErrResp { rs: false, detail }).unwrap());
+                    }
+                },
+                _ => {}
             }
         }
-        if let Some(_) = message.got_topic() {
+        if message.got_topic().is_some() {
             // if got topic, it's a message, run dispatch!
-            self.dispatch_message(message);
+            self.dispatch_message(message, ctx);
         }
     }
 
-    fn dispatch_message(&mut self, message: &mut Message) {
-        self.dispacher.dispach_message(message);
+    /// Store the message and confirm it to the publisher once it is persisted
+    fn dispatch_message(&mut self, message: &mut Message, ctx: &mut <WsSession as Actor>::Context) {
+        let mut dispacher = self.dispacher.clone();
+        let mut message = message.clone();
+        let uid = message.uid.clone();
+        let fut = async move { dispacher.publish(&mut message).await };
+        ctx.spawn(fut.into_actor(self).map(move |rs, _act, ctx| {
+            let json = match rs {
+                Ok(receipt) => serde_json::to_string(&ConfirmResp { rs: true, uid, nonce: receipt.nonce, delay_id: receipt.delay_id }),
+                Err(detail) => serde_json::to_string(&ConfirmErrResp { rs: false, uid, detail }),
+            };
+            ctx.text(json.unwrap());
+        }));
+    }
+
+    /// Store a batch and confirm all of its uids with their nonces once persisted
+    fn dispatch_batch(&mut self, messages: Vec<Message>, ctx: &mut <WsSession as Actor>::Context) {
+        let mut dispacher = self.dispacher.clone();
+        let uids: Vec<String> = messages.iter().map(|m| m.uid.clone()).collect();
+        let fut = async move { dispacher.publish_batch(messages).await };
+        ctx.spawn(fut.into_actor(self).map(move |rs, _act, ctx| {
+            let json = match rs {
+                Ok(nonces) => serde_json::to_string(&BatchConfirmResp { rs: true, uids, nonces }),
+                Err(detail) => serde_json::to_string(&ErrResp { rs: false, detail }),
+            };
+            ctx.text(json.unwrap());
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_matches_zero_or_more_levels() {
+        assert!(topic_matches("orders.#", "orders"));
+        assert!(topic_matches("orders.#", "orders.eu.created"));
+        assert!(topic_matches("#.created", "created"));
+        assert!(topic_matches("orders.#.created", "orders.created"));
+        assert!(!topic_matches("orders.#", "payments.eu"));
+    }
+
+    #[test]
+    fn star_matches_exactly_one_level() {
+        assert!(topic_matches("orders.*.created", "orders.eu.created"));
+        assert!(!topic_matches("orders.*.created", "orders.created"));
+        assert!(!topic_matches("orders.*", "orders.eu.created"));
+    }
+
+    #[test]
+    fn dead_letters_need_a_dlq_pattern() {
+        assert!(!topic_matches("orders.#", "orders.eu.dlq"));
+        assert!(!topic_matches("#", "orders.dlq"));
+        assert!(topic_matches("orders.*.dlq", "orders.eu.dlq"));
+        assert!(topic_matches("#.dlq", "orders.dlq"));
+    }
+
+    fn message(json: &str) -> Message {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn frame_keeps_header_and_raw_body() {
+        let mut sent = message(r#"{"uid":"u1","topic":"t","nonce":7}"#);
+        sent.body = Some(vec![0, 159, 146, 150]);
+        let got = Message::from_frame(&sent.to_frame()).unwrap();
+        assert_eq!(got.uid, "u1");
+        assert_eq!(got.nonce, Some(7));
+        assert_eq!(got.body, Some(vec![0, 159, 146, 150]));
+    }
+
+    #[test]
+    fn frame_with_empty_body() {
+        let frame = message(r#"{"uid":"u2","topic":"t"}"#).to_frame();
+        let got = Message::from_frame(&frame).unwrap();
+        assert_eq!(got.uid, "u2");
+        assert_eq!(got.body, Some(vec![]));
+        assert_eq!(got.payload, None);
+    }
+
+    #[test]
+    fn truncated_frame_header_is_rejected() {
+        assert!(Message::from_frame(&[0, 0]).is_err());
+        let frame = message(r#"{"uid":"u3","topic":"t"}"#).to_frame();
+        let len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+        let err = Message::from_frame(&frame[..4 + len - 1]).unwrap_err();
+        assert_eq!(err, format!("Header length {len} exceeds frame"));
     }
 }